@@ -0,0 +1,111 @@
+//! A cache for the output of commands, so that slow ones (such as those that
+//! fetch credentials) need not be run every time an environment is set.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Context, eyre};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    /// When this entry expires, in seconds since the Unix epoch.
+    expires: u64,
+    output: String,
+}
+
+/// The directory we cache to: `$XDG_CACHE_HOME/envswitch`, falling back to
+/// `~/.cache/envswitch`.
+pub fn dir() -> eyre::Result<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::home_dir()
+            .ok_or_else(|| eyre!("Could not determine home directory"))?
+            .join(".cache"),
+    };
+    Ok(base.join("envswitch"))
+}
+
+/// The name of the cache entry for `command`, as found in `config`. Any change
+/// to the command gives it a new entry.
+pub fn key(config: &Path, command: &str) -> String {
+    let config = fs::canonicalize(config).unwrap_or_else(|_| config.to_path_buf());
+    let mut hasher = Sha256::new();
+    hasher.update(config.as_os_str().as_encoded_bytes());
+    hasher.update([0]);
+    hasher.update(command.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Get the cached output for `key`, if there is any that hasn't expired.
+///
+/// A missing or unreadable entry is treated the same as an expired one, as we
+/// can always run the command again.
+pub fn get(key: &str) -> Option<String> {
+    let contents = fs::read_to_string(dir().ok()?.join(key)).ok()?;
+    let entry: Entry = toml::from_str(&contents).ok()?;
+    (now() < entry.expires).then_some(entry.output)
+}
+
+/// Cache `output` for `key`, for `ttl`. The file is only readable by the user,
+/// as it may well contain secrets.
+pub fn put(key: &str, output: &str, ttl: Duration) -> eyre::Result<()> {
+    let dir = dir()?;
+    let path = dir.join(key);
+    let entry = Entry {
+        expires: now().saturating_add(ttl.as_secs()),
+        output: output.to_string(),
+    };
+
+    let write = || -> eyre::Result<()> {
+        fs::create_dir_all(&dir)?;
+        // Write to a temporary file first, so that a concurrent read never
+        // sees a partial entry.
+        let tmp = dir.join(format!("{key}.{}", std::process::id()));
+        let mut file = open_private(&tmp)?;
+        file.write_all(toml::to_string(&entry)?.as_bytes())?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    };
+    write().wrap_err_with(|| eyre!("Failed to write cache file {}", path.display()))
+}
+
+/// Remove every cache entry.
+pub fn clear() -> eyre::Result<()> {
+    let dir = dir()?;
+    match fs::remove_dir_all(&dir) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).wrap_err_with(|| eyre!("Failed to remove {}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn open_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn open_private(path: &Path) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
@@ -1,18 +1,25 @@
-use std::{
-    fs,
-    path::{Path, PathBuf},
-};
+use std::{env, fs, path::PathBuf};
 
 use clap::{Args, Parser, Subcommand, ValueHint};
 use eyre::{Context, eyre};
 
-use crate::{config::Table, shell::Shell};
+use crate::{
+    config::{self, Origins, Table},
+    shell::Shell,
+};
 
 const ABOUT: &str = "A simple tool for managing sets of environment variables
 
 Run with no arguments to see the current environment setting.";
 
-const DEFAULT_FILE: &str = "./envswitch.toml";
+const FILE_NAME: &str = "envswitch.toml";
+
+/// Merged on top of the project's config file, from the same directory, for
+/// settings that shouldn't be committed.
+const LOCAL_FILE_NAME: &str = "envswitch.local.toml";
+
+/// If set, we don't look for a config file above the root of a git repository.
+const STOP_AT_GIT_VAR: &str = "ENVSWITCH_STOP_AT_GIT";
 
 #[derive(Parser, Debug)]
 #[command(version, about = ABOUT)]
@@ -24,11 +31,18 @@ pub struct Cli {
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Show the name of the current environment
-    Get,
+    Get(Get),
     /// Set the environment
     Set(Set),
     /// Generate a command to integrate envswitch with your shell
     Setup(Setup),
+    /// Manage the cache of command outputs
+    Cache(Cache),
+    /// Encrypt a variable's value in the config file with age
+    Encrypt(Encrypt),
+    /// Re-encrypt every encrypted value in the config file, such as after
+    /// changing recipients
+    Rekey(Rekey),
     #[clap(hide = true)]
     Complete(Complete),
 }
@@ -40,10 +54,11 @@ pub enum Commands {
 pub struct Set {
     #[command(flatten)]
     pub config: ConfigPath,
-    /// The name of the environment to select; leave blank to only set global
-    /// options.
-    #[arg(default_value = "", value_hint = ValueHint::Other)]
-    pub env: String,
+    /// The names of the environments to select, which are merged from left
+    /// to right, and may also be joined with `+`; leave blank to only set
+    /// global options.
+    #[arg(value_hint = ValueHint::Other)]
+    pub envs: Vec<String>,
 
     #[arg(short, long)]
     pub shell: Shell,
@@ -51,54 +66,214 @@ pub struct Set {
     /// List available environments instead of setting any.
     #[arg(short, long)]
     pub list: bool,
+
+    /// Only list environments with this tag; may be repeated.
+    #[arg(short, long, requires = "list")]
+    pub tag: Vec<String>,
+
+    /// Show which config file each variable would be set from, instead of
+    /// setting any.
+    #[arg(short, long)]
+    pub explain: bool,
+
+    /// Keep the environment's state separately from that of other namespaces,
+    /// so that setting one doesn't clear another.
+    #[arg(long)]
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Get {
+    #[command(flatten)]
+    pub config: ConfigPath,
+
+    /// Also show the config files that would be used here.
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Show the environment set in this namespace instead of the default one.
+    #[arg(long, conflicts_with = "all")]
+    pub namespace: Option<String>,
+
+    /// Show the environment set in each namespace, prefixed by the namespace's
+    /// name for all but the default one.
+    #[arg(short, long)]
+    pub all: bool,
 }
 
 #[derive(Debug, Clone, Args)]
 pub struct ConfigPath {
-    #[arg(short, long, value_hint = ValueHint::FilePath, help = "path to config file [defaults: ./envswitch.toml]")]
+    #[arg(short, long, value_hint = ValueHint::FilePath, help = "path to config file [default: the nearest envswitch.toml]")]
     pub file: Option<PathBuf>,
 }
 
+impl ConfigPath {
+    /// The path to the config file: the one given, or else the nearest one we
+    /// can find.
+    pub fn find(&self) -> eyre::Result<Option<PathBuf>> {
+        match &self.file {
+            Some(file) => Ok(Some(file.clone())),
+            None => discover().wrap_err("Failed to search for envswitch.toml"),
+        }
+    }
+
+    /// The config files to merge, from least to most specific: the user's
+    /// config, the project's config, and its local overrides, for those that
+    /// exist. A file given explicitly is used alone, so that it means the same
+    /// thing from anywhere.
+    pub fn files(&self) -> eyre::Result<Vec<PathBuf>> {
+        if let Some(file) = &self.file {
+            return Ok(vec![file.clone()]);
+        }
+        let mut files = Vec::new();
+        if let Some(user) = user_config()
+            && fs::exists(&user)?
+        {
+            files.push(user);
+        }
+        if let Some(file) = self.find()? {
+            let local = file.with_file_name(LOCAL_FILE_NAME);
+            files.push(file);
+            if fs::exists(&local)? {
+                files.push(local);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Like `find`, for when we need a file.
+    pub fn require(&self) -> eyre::Result<PathBuf> {
+        self.find()?.ok_or_else(|| {
+            eyre!("No {FILE_NAME} found in this directory or any above it; use -f to give a path")
+        })
+    }
+}
+
+/// Find the nearest config file, searching from the current directory upward.
+/// If `$ENVSWITCH_STOP_AT_GIT` is set, we stop at the root of a git repository.
+fn discover() -> eyre::Result<Option<PathBuf>> {
+    let stop_at_git = env::var_os(STOP_AT_GIT_VAR).is_some_and(|var| !var.is_empty());
+    let cwd = env::current_dir()?;
+    for dir in cwd.ancestors() {
+        let file = dir.join(FILE_NAME);
+        if fs::exists(&file)? {
+            return Ok(Some(file));
+        }
+        if stop_at_git && fs::exists(dir.join(".git"))? {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// The user's own config file, `$XDG_CONFIG_HOME/envswitch/config.toml`,
+/// falling back to `~/.config/envswitch/config.toml`.
+fn user_config() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::home_dir()?.join(".config"),
+    };
+    Some(base.join("envswitch").join("config.toml"))
+}
+
 #[derive(Debug, Clone, Args)]
 pub struct Complete {
     #[command(flatten)]
     pub config: ConfigPath,
 
-    // NOTE: We accept multiple positional arguments here just to make completion
-    // not error if you hit TAB too many times. We only use the first one.
+    /// The environments given so far, the last of which we complete.
     #[arg(default_value = "")]
     pub env: Vec<String>,
 
-    // We don't use this, but we need to respect all arguments that might be
+    /// Only complete environments with this tag.
+    #[arg(short, long)]
+    pub tag: Vec<String>,
+
+    /// The shell to format completions for, which may show descriptions.
+    #[arg(long)]
+    pub shell: Option<Shell>,
+
+    // We don't use these, but we need to respect all arguments that might be
     // passed into `es`.
     #[arg(short, long)]
     list: bool,
+    #[arg(short, long)]
+    explain: bool,
+    #[arg(long)]
+    namespace: Option<String>,
 }
 
 #[derive(Debug, Clone, Args)]
 pub struct Setup {
     pub shell: Shell,
+
+    /// The name of the function to define.
+    #[arg(long, default_value = "es")]
+    pub name: String,
+
+    /// Keep the function's state in this namespace, so that it can be used
+    /// alongside functions for other namespaces.
+    #[arg(long)]
+    pub namespace: Option<String>,
+
+    /// Always use this config file, instead of searching for one.
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    pub file: Option<PathBuf>,
 }
 
-pub fn load_config_file(path: Option<&Path>) -> eyre::Result<Table> {
-    fn load_file_inner(path: Option<&Path>) -> eyre::Result<Table> {
-        let bytes = match path {
-            Some(path) => fs::read(path)?,
-            None => {
-                if fs::exists(DEFAULT_FILE)? {
-                    fs::read(DEFAULT_FILE)?
-                } else {
-                    Vec::new()
-                }
-            }
-        };
-        let config: Table = toml::from_slice(&bytes)?;
+#[derive(Debug, Clone, Args)]
+pub struct Encrypt {
+    #[command(flatten)]
+    pub config: ConfigPath,
+    /// The environment the variable is set in; leave blank for global
+    /// variables.
+    pub env: String,
+    /// The variable whose value to encrypt.
+    pub var: String,
+    #[command(flatten)]
+    pub recipients: Recipients,
+}
 
-        Ok(config)
-    }
+#[derive(Debug, Clone, Args)]
+pub struct Rekey {
+    #[command(flatten)]
+    pub config: ConfigPath,
+    #[command(flatten)]
+    pub recipients: Recipients,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct Recipients {
+    /// A public key to encrypt to; may be repeated [default: the public keys
+    /// of $ENVSWITCH_AGE_IDENTITY]
+    #[arg(short, long = "recipient")]
+    pub recipients: Vec<String>,
+    /// A file of public keys to encrypt to, one per line; may be repeated
+    #[arg(short = 'R', long = "recipients-file", value_hint = ValueHint::FilePath)]
+    pub recipients_files: Vec<PathBuf>,
+}
 
-    load_file_inner(path).wrap_err_with(|| match path {
-        Some(p) => eyre!("Failed to read file {}", p.display()),
-        None => eyre!("Failed to read default file {DEFAULT_FILE}"),
-    })
+#[derive(Debug, Clone, Args)]
+pub struct Cache {
+    #[command(subcommand)]
+    pub command: CacheCommand,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum CacheCommand {
+    /// Remove all cached command outputs
+    Clear,
+}
+
+/// Load and merge the config from each of `files`, with later files taking
+/// precedence. Also returns which file each value came from.
+pub fn load_config(files: &[PathBuf]) -> eyre::Result<(Table, Origins)> {
+    let mut config = Table::new();
+    let mut origins = Origins::new();
+    for file in files {
+        let (table, file_origins) = config::load(file)?;
+        config::merge(&mut config, table);
+        origins.extend(file_origins);
+    }
+    Ok((config, origins))
 }
@@ -1,10 +1,27 @@
-use std::{fmt, ops::Deref};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    fmt, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
 
-use indexmap::IndexMap;
+use clap::ValueEnum;
+use eyre::{Context, eyre};
+use indexmap::{IndexMap, map::Entry};
+use itertools::Itertools;
 use phf::{Set, phf_set};
-use serde::{
-    Deserialize,
-    de::{self, Visitor},
+use serde::{Deserialize, de};
+
+use crate::{
+    generate::{self, GeneratedValue},
+    hooks::Hooks,
+    provider,
+    shell::Shell,
+    sops,
+    source::{
+        AgeSource, CommandSource, ExtractSource, FileSource, PromptSource, ProviderSource, has_form,
+    },
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -24,13 +41,19 @@ impl Deref for Key {
     }
 }
 
+impl Borrow<str> for Key {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
 impl AsRef<str> for Key {
     fn as_ref(&self) -> &str {
         &self.0
     }
 }
 
-static FORBIDDEN_CHARS: Set<char> = phf_set! {'.', ',', ':', ' ', ';'};
+static FORBIDDEN_CHARS: Set<char> = phf_set! {'.', ',', ':', ' ', ';', '+'};
 
 impl TryFrom<String> for Key {
     type Error = eyre::Error;
@@ -43,93 +66,678 @@ impl TryFrom<String> for Key {
     }
 }
 
-impl<'de> Deserialize<'de> for Key {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        Key::try_from(s).map_err(serde::de::Error::custom)
-    }
-}
-
 pub type Table = IndexMap<Key, Value>;
 
+/// The file that each value in a merged config came from, by its path of keys.
+pub type Origins = HashMap<String, PathBuf>;
+
+/// The key for generating environments from other tools' configs, rather than
+/// a variable.
+const GENERATE_KEY: &str = "generate";
+
+/// The key for including other config files in a table, rather than a
+/// variable.
+const INCLUDE_KEY: &str = "include";
+
+/// The key for the tables whose variables a table inherits.
+const EXTENDS_KEY: &str = "extends";
+
+/// The key for notes about an environment, which are never exported.
+const META_KEY: &str = "_meta";
+
+/// The key for commands to run when entering or leaving an environment.
+const HOOKS_KEY: &str = "_hooks";
+
+/// The key for shell aliases that exist only while an environment is active.
+const ALIASES_KEY: &str = "_aliases";
+
 #[derive(Debug)]
 pub enum Value {
     String(String),
     Table(Table),
+    Splice(Splice),
+    /// Remove the variable, even if a less specific level sets it.
+    Unset,
+    File(FileSource),
+    Command(CommandSource),
+    Prompt(PromptSource),
+    Age(AgeSource),
+    /// Fetched along with every other value from the same provider.
+    Provider(ProviderSource),
+    /// Sets several variables, rather than the one named by its key.
+    Extract(ExtractSource),
+    /// The paths to other tables whose variables this table inherits, in
+    /// order.
+    Extends(Vec<String>),
+    Meta(Meta),
+    Hooks(Hooks),
+    /// Shell aliases, by name, each inherited by nested tables like a
+    /// variable.
+    Aliases(IndexMap<String, String>),
+    /// Set by a generated environment, rather than written in the config.
+    Generated(GeneratedValue),
 }
 
-pub fn deep_keys(table: &Table) -> impl Iterator<Item = String> {
-    fn collect_keys(table: &Table, prefix: &str, keys: &mut Vec<String>) {
+/// Notes about an environment, for listing it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Meta {
+    pub description: Option<String>,
+    /// Tags to filter the list of environments by, which apply to nested
+    /// environments as well.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Leave the environment out of lists and completions, though it can
+    /// still be selected.
+    #[serde(default)]
+    pub hidden: bool,
+    /// The color to list the environment in.
+    pub color: Option<Color>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Color {
+    /// Wrap `text` in the escape codes to show it in this color in a terminal.
+    pub fn paint(self, text: &str) -> String {
+        let code = match self {
+            Color::Black => 30,
+            Color::Red => 31,
+            Color::Green => 32,
+            Color::Yellow => 33,
+            Color::Blue => 34,
+            Color::Magenta => 35,
+            Color::Cyan => 36,
+            Color::White => 37,
+        };
+        format!("\x1b[{code}m{text}\x1b[0m")
+    }
+}
+
+/// A value that is added to the start and/or end of a variable's existing
+/// value, such as a directory for `PATH`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Splice {
+    #[serde(default, deserialize_with = "deserialize_segments")]
+    pub prepend: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_segments")]
+    pub append: Vec<String>,
+    #[serde(default = "default_separator")]
+    pub separator: String,
+}
+
+impl Splice {
+    fn matches(table: &toml::Table) -> bool {
+        has_form(
+            table,
+            &["prepend", "append"],
+            &["prepend", "append", "separator"],
+        )
+    }
+}
+
+/// A scalar or array, joined with a custom separator.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Joined {
+    value: toml::Value,
+    #[serde(default = "default_separator")]
+    separator: String,
+}
+
+impl Joined {
+    fn matches(table: &toml::Table) -> bool {
+        has_form(table, &["value"], &["value", "separator"])
+    }
+}
+
+fn default_separator() -> String {
+    ":".to_string()
+}
+
+/// Deserialize either a single segment or an array of them.
+fn deserialize_segments<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match toml::Value::deserialize(deserializer)? {
+        toml::Value::Array(values) => values
+            .into_iter()
+            .map(scalar_to_string)
+            .collect::<eyre::Result<_>>(),
+        value => scalar_to_string(value).map(|value| vec![value]),
+    }
+    .map_err(de::Error::custom)
+}
+
+/// Convert a TOML value that isn't a table into what we set a variable to.
+/// Arrays are joined with `separator`, and may only contain scalars.
+fn to_var_string(value: toml::Value, separator: &str) -> eyre::Result<String> {
+    match value {
+        toml::Value::Array(values) => Ok(values
+            .into_iter()
+            .map(scalar_to_string)
+            .collect::<eyre::Result<Vec<_>>>()?
+            .join(separator)),
+        value => scalar_to_string(value),
+    }
+}
+
+fn scalar_to_string(value: toml::Value) -> eyre::Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        // We use TOML's formatting for these, which ensures that e.g. `1.0` is
+        // not shown as `1`.
+        value @ (toml::Value::Float(_) | toml::Value::Datetime(_)) => Ok(value.to_string()),
+        other => Err(eyre!(
+            "expected a string, number, boolean, or datetime, found {}",
+            other.type_str()
+        )),
+    }
+}
+
+/// The table form of `Value::Unset`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Unset {
+    unset: bool,
+}
+
+impl Unset {
+    fn matches(table: &toml::Table) -> bool {
+        has_form(table, &["unset"], &["unset"])
+    }
+}
+
+pub fn join_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+/// Whether `key` is for a table that is only meant to be extended, rather than
+/// selected.
+pub fn is_abstract(key: &str) -> bool {
+    key.starts_with('_')
+}
+
+/// An environment that can be selected, as listed by `deep_keys`.
+#[derive(Debug)]
+pub struct Environment<'a> {
+    pub name: String,
+    pub meta: Option<&'a Meta>,
+    /// The environment's tags, including those of the tables it's nested in.
+    pub tags: Vec<&'a str>,
+}
+
+impl Environment<'_> {
+    /// Whether the environment has every one of `tags`.
+    pub fn has_tags(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.tags.contains(&tag.as_str()))
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.meta?.description.as_deref()
+    }
+}
+
+/// Every environment that should be listed, leaving out abstract and hidden
+/// ones.
+pub fn deep_keys(table: &Table) -> impl Iterator<Item = Environment<'_>> {
+    fn collect_keys<'a>(
+        table: &'a Table,
+        prefix: &str,
+        tags: &[&'a str],
+        keys: &mut Vec<Environment<'a>>,
+    ) {
         for (key, value) in table {
             let Some(table) = value.as_table() else {
                 continue;
             };
-            let full_key = if prefix.is_empty() {
-                key.0.clone()
-            } else {
-                format!("{}.{}", prefix, key)
-            };
+            if is_abstract(key) {
+                continue;
+            }
+            let full_key = join_path(prefix, key);
+            let meta = table.get(META_KEY).and_then(Value::as_meta);
+            let tags = tags
+                .iter()
+                .copied()
+                .chain(
+                    meta.iter()
+                        .flat_map(|meta| meta.tags.iter().map(String::as_str)),
+                )
+                .unique()
+                .collect::<Vec<_>>();
 
-            keys.push(full_key.clone());
+            if !meta.is_some_and(|meta| meta.hidden) {
+                keys.push(Environment {
+                    name: full_key.clone(),
+                    meta,
+                    tags: tags.clone(),
+                });
+            }
 
-            collect_keys(table, &full_key, keys);
+            collect_keys(table, &full_key, &tags, keys);
         }
     }
 
     let mut keys = Vec::new();
-    collect_keys(table, "", &mut keys);
+    collect_keys(table, "", &[], &mut keys);
     keys.into_iter()
 }
 
-impl<'de> Deserialize<'de> for Value {
-    // We implement this ourselves instead of using `#[serde(untagged)]` to get
-    // better error messages.
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct ValueVisitor;
+/// Merge `other` into `table`. Tables at the same path are merged in turn, and
+/// any other value in `other` replaces what `table` has.
+pub fn merge(table: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (table.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(inner)) => merge(existing, inner),
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Load the config from `file`, along with where each value in it came from,
+/// as values may come from the files it includes.
+pub fn load(file: &Path) -> eyre::Result<(Table, Origins)> {
+    let load_inner = || -> eyre::Result<_> {
+        let chain = [fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf())];
+        let (config, origins) = load_file(file, &chain)?;
+        validate(&config)?;
+        Ok((config, origins))
+    };
+    load_inner().wrap_err_with(|| eyre!("Failed to read file {}", file.display()))
+}
+
+/// Load `file`, which `chain` ends with; the rest of `chain` are the files that
+/// included it.
+fn load_file(file: &Path, chain: &[PathBuf]) -> eyre::Result<(Table, Origins)> {
+    let bytes = fs::read(file)?;
+    let mut raw = parse_document(&bytes, file)?;
+    if sops::is_encrypted(&raw) {
+        raw = sops::decrypt(raw).wrap_err("Failed to decrypt sops file")?;
+    }
+    let mut parser = FileParser {
+        file,
+        chain,
+        origins: Origins::new(),
+    };
+    let config = parser.parse_table(raw, "")?;
+    Ok((config, parser.origins))
+}
+
+/// Parse a config file as YAML or JSON if it has that extension, and as TOML
+/// otherwise. The former are mostly useful for files encrypted with sops, which
+/// doesn't support TOML itself.
+fn parse_document(bytes: &[u8], file: &Path) -> eyre::Result<toml::Table> {
+    let extension = file.extension().and_then(|ext| ext.to_str());
+    let document = match extension {
+        Some("yaml" | "yml") => serde_yaml::from_slice(bytes)?,
+        Some("json") => serde_json::from_slice(bytes)?,
+        _ => toml::from_slice(bytes)?,
+    };
+    Ok(document)
+}
+
+/// Converts a parsed TOML document into our config.
+///
+/// We do this ourselves instead of deriving `Deserialize` so that tables can be
+/// either environments or special values, and to get better error messages.
+struct FileParser<'a> {
+    /// The path to the config file, which relative paths in values are
+    /// relative to.
+    file: &'a Path,
+    /// The files that included this one, ending with it.
+    chain: &'a [PathBuf],
+    origins: Origins,
+}
 
-        impl<'de> Visitor<'de> for ValueVisitor {
-            type Value = Value;
+impl FileParser<'_> {
+    fn parse_table(&mut self, mut raw: toml::Table, prefix: &str) -> eyre::Result<Table> {
+        let generator = raw.remove(GENERATE_KEY);
+        let extends = raw.remove(EXTENDS_KEY);
+        let meta = raw.remove(META_KEY);
+        let hooks = raw.remove(HOOKS_KEY);
+        let aliases = raw.remove(ALIASES_KEY);
+        // Included files come first, so that this file takes precedence.
+        let mut table = match raw.remove(INCLUDE_KEY) {
+            Some(include) => self.include(include, prefix)?,
+            None => Table::new(),
+        };
+
+        let mut own = Table::new();
+        for (key, value) in raw {
+            let path = join_path(prefix, &key);
+            let key = Key::try_from(key).wrap_err_with(|| eyre!("Invalid key '{path}'"))?;
+            let value = self.parse_value(value, &path)?;
+            self.origins.insert(path, self.file.to_path_buf());
+            own.insert(key, value);
+        }
+        if let Some(extends) = extends {
+            let path = join_path(prefix, EXTENDS_KEY);
+            let tables = one_or_many(extends)
+                .and_then(|tables| {
+                    for table in &tables {
+                        for key in table.split('.') {
+                            Key::try_from(key.to_string())?;
+                        }
+                    }
+                    Ok(tables)
+                })
+                .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+            self.origins.insert(path, self.file.to_path_buf());
+            own.insert(Key(EXTENDS_KEY.to_string()), Value::Extends(tables));
+        }
+        if let Some(meta) = meta {
+            let path = join_path(prefix, META_KEY);
+            let meta: Meta = meta
+                .try_into()
+                .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+            self.origins.insert(path, self.file.to_path_buf());
+            own.insert(Key(META_KEY.to_string()), Value::Meta(meta));
+        }
+        if let Some(hooks) = hooks {
+            let path = join_path(prefix, HOOKS_KEY);
+            let hooks: Hooks = hooks
+                .try_into()
+                .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+            self.origins.insert(path, self.file.to_path_buf());
+            own.insert(Key(HOOKS_KEY.to_string()), Value::Hooks(hooks));
+        }
+        if let Some(aliases) = aliases {
+            let path = join_path(prefix, ALIASES_KEY);
+            let aliases: IndexMap<String, String> = aliases
+                .try_into()
+                .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+            for name in aliases.keys() {
+                check_alias_name(name)
+                    .wrap_err_with(|| eyre!("Invalid alias '{name}' in '{path}'"))?;
+            }
+            self.origins.insert(path, self.file.to_path_buf());
+            own.insert(Key(ALIASES_KEY.to_string()), Value::Aliases(aliases));
+        }
+        merge(&mut table, own);
+
+        if let Some(generator) = generator {
+            let path = join_path(prefix, GENERATE_KEY);
+            let generated = generator
+                .as_str()
+                .ok_or_else(|| eyre!("expected a string, found {}", generator.type_str()))
+                .and_then(generate::generate)
+                .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+            for (name, vars) in generated {
+                // Skip any names we couldn't select, and let tables written by
+                // hand take precedence.
+                let Ok(key) = Key::try_from(name) else {
+                    continue;
+                };
+                if let Entry::Vacant(entry) = table.entry(key) {
+                    let path = join_path(prefix, entry.key());
+                    for var in vars.keys() {
+                        let var_path = join_path(&path, var);
+                        self.origins.insert(var_path, self.file.to_path_buf());
+                    }
+                    let vars = vars
+                        .into_iter()
+                        .map(|(var, value)| (Key(var.to_string()), Value::Generated(value)));
+                    entry.insert(Value::Table(vars.collect()));
+                }
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Load the files that `include` names, merged in order, to go at
+    /// `prefix`.
+    fn include(&mut self, include: toml::Value, prefix: &str) -> eyre::Result<Table> {
+        let path = join_path(prefix, INCLUDE_KEY);
+        let files = one_or_many(include)
+            .and_then(|patterns| {
+                patterns
+                    .iter()
+                    .map(|pattern| self.expand_include(pattern))
+                    .flatten_ok()
+                    .collect::<eyre::Result<Vec<_>>>()
+            })
+            .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+
+        let mut table = Table::new();
+        for file in files {
+            let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+            let cycle = self.chain.contains(&canonical);
+            let chain = [self.chain, &[canonical]].concat();
+            let describe_chain = || chain.iter().map(|file| file.display()).join(" -> ");
+            if cycle {
+                return Err(eyre!("Include cycle: {}", describe_chain()));
+            }
 
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("a string or table")
+            let (included, origins) = load_file(&file, &chain)
+                .wrap_err_with(|| eyre!("Failed to include {}", describe_chain()))?;
+            merge(&mut table, included);
+            for (path, file) in origins {
+                self.origins.insert(join_path(prefix, &path), file);
             }
+        }
+        Ok(table)
+    }
+
+    /// The files matching `pattern`, relative to this file.
+    fn expand_include(&self, pattern: &str) -> eyre::Result<Vec<PathBuf>> {
+        let dir = self.file.parent().unwrap_or(Path::new(""));
+        // A path that isn't a glob must exist, rather than matching nothing.
+        if !pattern.contains(['*', '?', '[']) {
+            return Ok(vec![dir.join(pattern)]);
+        }
 
-            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-            where
-                E: de::Error,
-            {
-                Ok(Value::String(v.to_owned()))
+        let dir = dir
+            .to_str()
+            .ok_or_else(|| eyre!("{} is not valid UTF-8", dir.display()))?;
+        let pattern = Path::new(&glob::Pattern::escape(dir)).join(pattern);
+        let files = glob::glob(&pattern.to_string_lossy())
+            .wrap_err_with(|| eyre!("Invalid pattern '{}'", pattern.display()))?
+            .collect::<Result<_, _>>()?;
+        Ok(files)
+    }
+
+    fn parse_value(&mut self, raw: toml::Value, path: &str) -> eyre::Result<Value> {
+        let dir = self.file.parent().unwrap_or(Path::new(""));
+        match raw {
+            toml::Value::Table(table) if Splice::matches(&table) => {
+                let splice: Splice = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                if splice.separator.is_empty() {
+                    return Err(eyre!(
+                        "Invalid value for '{path}': separator cannot be empty"
+                    ));
+                }
+                Ok(Value::Splice(splice))
+            }
+            toml::Value::Table(table) if Unset::matches(&table) => {
+                let Unset { unset } = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                if !unset {
+                    return Err(eyre!(
+                        "Invalid value for '{path}': unset must be true; remove it to keep the variable"
+                    ));
+                }
+                Ok(Value::Unset)
+            }
+            toml::Value::Table(table) if Joined::matches(&table) => {
+                let Joined { value, separator } = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                to_var_string(value, &separator)
+                    .map(Value::String)
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))
             }
+            toml::Value::Table(table) if ExtractSource::matches(&table) => {
+                let source = ExtractSource::parse(table, self.file)
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                Ok(Value::Extract(source))
+            }
+            toml::Value::Table(table) if FileSource::matches(&table) => {
+                let source: FileSource = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                Ok(Value::File(source.with_dir(dir)))
+            }
+            toml::Value::Table(table) if CommandSource::matches(&table) => {
+                let source: CommandSource = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                Ok(Value::Command(source.with_config(self.file)))
+            }
+            toml::Value::Table(table) if PromptSource::matches(&table) => {
+                let source: PromptSource = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                Ok(Value::Prompt(source))
+            }
+            toml::Value::Table(table) if AgeSource::matches(&table) => {
+                let source: AgeSource = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                Ok(Value::Age(source))
+            }
+            toml::Value::Table(table) if ProviderSource::matches(&table) => {
+                let source: ProviderSource = toml::Value::Table(table)
+                    .try_into()
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                provider::check_name(&source.provider)
+                    .wrap_err_with(|| eyre!("Invalid value for '{path}'"))?;
+                Ok(Value::Provider(source))
+            }
+            toml::Value::Table(table) => self.parse_table(table, path).map(Value::Table),
+            other => to_var_string(other, &default_separator())
+                .map(Value::String)
+                .wrap_err_with(|| eyre!("Invalid value for '{path}'")),
+        }
+    }
+}
+
+/// Either a single string or an array of them, such as the paths in an
+/// `include`.
+fn one_or_many(value: toml::Value) -> eyre::Result<Vec<String>> {
+    match value {
+        toml::Value::String(value) => Ok(vec![value]),
+        toml::Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                toml::Value::String(value) => Ok(value),
+                other => Err(eyre!("expected a string, found {}", other.type_str())),
+            })
+            .collect(),
+        other => Err(eyre!(
+            "expected a string or array, found {}",
+            other.type_str()
+        )),
+    }
+}
 
-            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
-            where
-                A: de::MapAccess<'de>,
-            {
-                Table::deserialize(de::value::MapAccessDeserializer::new(map)).map(Value::Table)
+/// Ensure that every variable in the config can be set by all of our shells, so
+/// that we never emit a broken (or malicious) command.
+pub fn validate(table: &Table) -> eyre::Result<()> {
+    fn validate_inner(table: &Table, prefix: &str) -> eyre::Result<()> {
+        for (key, value) in table {
+            match value {
+                Value::String(_)
+                | Value::Splice(_)
+                | Value::Unset
+                | Value::File(_)
+                | Value::Command(_)
+                | Value::Prompt(_)
+                | Value::Age(_)
+                | Value::Provider(_)
+                | Value::Generated(_) => check_var_name(key).map_err(|err| {
+                    if prefix.is_empty() {
+                        eyre!("Invalid variable '{key}': {err}")
+                    } else {
+                        eyre!("Invalid variable '{key}' in [{prefix}]: {err}")
+                    }
+                })?,
+                Value::Extract(source) => {
+                    for var in source.fields.keys() {
+                        check_var_name(var).map_err(|err| {
+                            let path = join_path(prefix, key);
+                            eyre!("Invalid variable '{var}' in '{path}': {err}")
+                        })?;
+                    }
+                }
+                Value::Table(table) => validate_inner(table, &join_path(prefix, key))?,
+                Value::Extends(_) | Value::Meta(_) | Value::Hooks(_) | Value::Aliases(_) => (),
             }
         }
+        Ok(())
+    }
+
+    validate_inner(table, "")
+}
 
-        deserializer.deserialize_any(ValueVisitor)
+/// Check that `name` is a valid alias in every shell we support, and needs no
+/// quoting.
+pub fn check_alias_name(name: &str) -> eyre::Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "use only letters, digits, '-', and '_', and do not start with '-'"
+        ))
+    }
+}
+
+/// Check that `name` is a valid variable name in every shell we support.
+pub fn check_var_name(name: &str) -> eyre::Result<()> {
+    let shells = Shell::value_variants()
+        .iter()
+        .filter(|shell| !shell.is_valid_var_name(name))
+        .join(", ");
+    if shells.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "not a valid variable name in {shells}; use only letters, digits, and underscores, \
+             and do not start with a digit"
+        ))
     }
 }
 
 impl Value {
-    pub fn as_string(&self) -> Option<&str> {
+    pub fn as_table(&self) -> Option<&Table> {
         match self {
-            Value::String(val) => Some(val),
+            Value::Table(val) => Some(val),
             _ => None,
         }
     }
 
-    pub fn as_table(&self) -> Option<&Table> {
+    pub fn as_meta(&self) -> Option<&Meta> {
         match self {
-            Value::Table(val) => Some(val),
+            Value::Meta(meta) => Some(meta),
             _ => None,
         }
     }
@@ -1,48 +1,292 @@
-use std::ops::Deref;
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    ptr,
+};
 
-use eyre::eyre;
+use eyre::{Context, eyre};
 use indexmap::IndexMap;
 use itertools::Itertools;
 
 use crate::{
-    config::{Key, Table},
+    config::{Key, Splice, Table, Value, is_abstract, join_path},
+    current_env::CurrentEnv,
+    hooks::Hook,
+    interpolate, provider,
     shell::Shell,
+    source::{self, ExtractSource, ProviderSource},
 };
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ConfigWalker<'a> {
-    pub vals: IndexMap<&'a str, &'a str>,
+    /// The whole config, to find the tables that others extend.
+    root: &'a Table,
+    /// Every value we've found for each variable, from least to most specific.
+    layers: IndexMap<&'a str, Vec<Layer<'a>>>,
+    /// The path of keys to each layer in `layers`, so we can say where it was
+    /// set.
+    paths: IndexMap<&'a str, Vec<String>>,
+    /// The final value of each variable; filled in by `resolve`.
+    pub vals: IndexMap<&'a str, Resolved>,
+    /// The hooks to run on entering and leaving: the most specific of each
+    /// for every environment we compose.
+    pub enter: Vec<&'a Hook>,
+    pub exit: Vec<&'a Hook>,
+    /// The most specific command for each alias.
+    pub aliases: IndexMap<&'a str, &'a str>,
+    /// Variables set by more than one of the environments we compose, with
+    /// the indices of the last two to set them.
+    pub conflicts: IndexMap<&'a str, (usize, usize)>,
+    /// The paths of the tables that earlier environments tracked the
+    /// variables of, so that a table shared by several environments, such as
+    /// the root, only counts once.
+    tracked: HashSet<String>,
+    /// The paths of the tables tracked for the environment we're walking.
+    /// Within one environment, a table that's extended again applies again.
+    walking_tracked: HashSet<String>,
+    /// The index of the environment we're walking, unless we're at the root,
+    /// which every environment shares.
+    env: Option<usize>,
+    /// The environment that last set each variable, to find conflicts.
+    owners: HashMap<&'a str, usize>,
+    /// The most specific hooks of the environment we're walking.
+    walking_enter: Option<&'a Hook>,
+    walking_exit: Option<&'a Hook>,
+}
+
+/// A value for a variable at one level of the config.
+#[derive(Debug, Clone, Copy)]
+enum Layer<'a> {
+    Value(&'a Value),
+    /// A field of a source that sets several variables.
+    Field {
+        source: &'a ExtractSource,
+        path: &'a str,
+    },
+}
+
+impl Layer<'_> {
+    fn as_splice(&self) -> Option<&Splice> {
+        match self {
+            Layer::Value(Value::Splice(splice)) => Some(splice),
+            _ => None,
+        }
+    }
+}
+
+/// What a variable will be set to, after combining every level of the config.
+#[derive(Debug, Clone)]
+pub enum Resolved {
+    /// Replace the variable's value entirely.
+    Set(String),
+    /// Add segments around the variable's existing value.
+    Splice {
+        prepend: Vec<String>,
+        append: Vec<String>,
+        separator: String,
+    },
+    /// Make sure the variable is not set.
+    Unset,
+}
+
+impl Resolved {
+    /// The value to set, given the value the variable would otherwise have, or
+    /// `None` if it should not be set.
+    pub fn apply(&self, base: Option<&str>) -> Option<String> {
+        match self {
+            Resolved::Set(value) => Some(value.clone()),
+            Resolved::Splice {
+                prepend,
+                append,
+                separator,
+            } => Some(
+                prepend
+                    .iter()
+                    .map(String::as_str)
+                    .chain(base)
+                    .chain(append.iter().map(String::as_str))
+                    .join(separator),
+            ),
+            Resolved::Unset => None,
+        }
+    }
+
+    /// Add the (already expanded) segments of `splice` to this value.
+    fn splice(
+        self,
+        splice: &Splice,
+        prepend: Vec<String>,
+        append: Vec<String>,
+    ) -> eyre::Result<Self> {
+        let resolved = match self {
+            Resolved::Set(value) => Resolved::Set(
+                prepend
+                    .into_iter()
+                    .chain([value])
+                    .chain(append)
+                    .join(&splice.separator),
+            ),
+            // A less specific level removed the variable, so there is nothing
+            // to add to.
+            Resolved::Unset => {
+                Resolved::Set(prepend.into_iter().chain(append).join(&splice.separator))
+            }
+            Resolved::Splice {
+                prepend: outer_prepend,
+                append: outer_append,
+                separator,
+            } => {
+                if separator != splice.separator {
+                    return Err(eyre!(
+                        "conflicting separators '{separator}' and '{}'",
+                        splice.separator
+                    ));
+                }
+                Resolved::Splice {
+                    prepend: prepend.into_iter().chain(outer_prepend).collect(),
+                    append: outer_append.into_iter().chain(append).collect(),
+                    separator,
+                }
+            }
+        };
+        Ok(resolved)
+    }
 }
 
 impl<'a> ConfigWalker<'a> {
-    pub fn new(config: &'a Table, keys: impl Iterator<Item = &'a Key>) -> eyre::Result<Self> {
-        let mut this = Self::default();
-        this.walk(config, keys)?;
+    /// Walk the config to each of `envs`, given as paths of keys, merging
+    /// them from left to right.
+    pub fn new(config: &'a Table, envs: &'a [Vec<Key>]) -> eyre::Result<Self> {
+        let mut this = Self {
+            root: config,
+            layers: IndexMap::new(),
+            paths: IndexMap::new(),
+            vals: IndexMap::new(),
+            enter: Vec::new(),
+            exit: Vec::new(),
+            aliases: IndexMap::new(),
+            conflicts: IndexMap::new(),
+            tracked: HashSet::new(),
+            walking_tracked: HashSet::new(),
+            env: None,
+            owners: HashMap::new(),
+            walking_enter: None,
+            walking_exit: None,
+        };
+        for (idx, keys) in envs.iter().enumerate() {
+            this.walking_enter = None;
+            this.walking_exit = None;
+            this.walk(config, keys.iter(), "", idx)?;
+            let walked = std::mem::take(&mut this.walking_tracked);
+            this.tracked.extend(walked);
+
+            // Environments may share hooks, such as those set at the root.
+            if let Some(enter) = this.walking_enter
+                && !this.enter.iter().any(|other| ptr::eq(*other, enter))
+            {
+                this.enter.push(enter);
+            }
+            if let Some(exit) = this.walking_exit
+                && !this.exit.iter().any(|other| ptr::eq(*other, exit))
+            {
+                this.exit.push(exit);
+            }
+        }
         Ok(this)
     }
 
-    pub fn set_commands(&self, shell: &Shell) -> impl Iterator<Item = String> {
-        self.vals
+    /// Determine the final value of each variable, expanding references to
+    /// other variables and reading any values from their sources. This must
+    /// be done after walking, so that every reference sees the most specific
+    /// value, and so that we only read values that are actually used.
+    pub fn resolve(&mut self, current_env: &CurrentEnv) -> eyre::Result<()> {
+        let mut resolver = Resolver {
+            layers: &self.layers,
+            current_env,
+            documents: HashMap::new(),
+            provided: HashMap::new(),
+            done: HashMap::new(),
+            stack: Vec::new(),
+        };
+        let vals = self
+            .layers
+            .keys()
+            .map(|var| Ok((*var, resolver.resolve(var)?)))
+            .collect::<eyre::Result<_>>()?;
+
+        self.vals = vals;
+        Ok(())
+    }
+
+    pub fn set_commands(
+        &self,
+        shell: &Shell,
+        current_env: &CurrentEnv,
+    ) -> impl Iterator<Item = String> {
+        self.vals.iter().filter_map(move |(var, resolved)| {
+            let base = current_env.base_value(var);
+            match resolved.apply(base.as_deref()) {
+                Some(value) => Some(shell.set_var(var, &value)),
+                // Only unset variables that would otherwise be set.
+                None => base.map(|_| shell.clear_var(var)),
+            }
+        })
+    }
+
+    pub fn alias_commands(
+        &self,
+        shell: &Shell,
+        namespace: Option<&str>,
+    ) -> impl Iterator<Item = String> {
+        self.aliases
             .iter()
-            .map(|(var, value)| shell.set_var(var, value))
+            .map(move |(name, command)| shell.set_alias(name, command, namespace))
     }
 
+    /// The variables that we set.
     pub fn variables(&self) -> String {
-        Itertools::intersperse(self.vals.keys().map(Deref::deref), " ").collect()
+        let vars = self
+            .vals
+            .iter()
+            .filter(|(_, resolved)| !matches!(resolved, Resolved::Unset))
+            .map(|(var, _)| *var);
+        Itertools::intersperse(vars, " ").collect()
+    }
+
+    /// The paths of keys to the values that make up each variable, from least
+    /// to most specific. Values that are overridden entirely are left out.
+    pub fn sources(&self) -> impl Iterator<Item = (&'a str, &[String])> {
+        self.layers.iter().map(|(var, layers)| {
+            let start = layers
+                .iter()
+                .rposition(|layer| layer.as_splice().is_none())
+                .unwrap_or(0);
+            (*var, &self.paths[var][start..])
+        })
     }
 
+    fn push(&mut self, var: &'a str, layer: Layer<'a>, path: String) {
+        if let Some(env) = self.env
+            && layer.as_splice().is_none()
+            && let Some(owner) = self.owners.insert(var, env)
+            && owner != env
+        {
+            self.conflicts.insert(var, (owner, env));
+        }
+        self.layers.entry(var).or_default().push(layer);
+        self.paths.entry(var).or_default().push(path);
+    }
+
+    /// Walk to the environment at index `env`, given by `keys`.
     fn walk(
         &mut self,
         config: &'a Table,
         mut keys: impl Iterator<Item = &'a Key>,
+        prefix: &str,
+        env: usize,
     ) -> eyre::Result<()> {
         // First we track any variables that are set at this level:
-        let variables = config
-            .iter()
-            .flat_map(|(k, v)| v.as_string().map(|v| (k, v)));
-        for (var, value) in variables {
-            self.vals.insert(var, value);
-        }
+        self.env = (!prefix.is_empty()).then_some(env);
+        self.track(config, prefix, &mut vec![prefix.to_string()])?;
 
         // Now we go to the next level:
         let Some(head) = keys.next() else {
@@ -54,7 +298,8 @@ impl<'a> ConfigWalker<'a> {
         let missing_key = || {
             let options = config
                 .iter()
-                .filter_map(|(k, v)| v.as_table().map(|_| k))
+                .filter(|(k, v)| v.as_table().is_some() && !is_abstract(k))
+                .map(|(k, _)| k)
                 .join(", ");
             eyre!("missing key '{head}'. Available options: {options}")
         };
@@ -64,8 +309,228 @@ impl<'a> ConfigWalker<'a> {
             .as_table()
             .ok_or_else(|| eyre!("key '{head}' does not correspond to a table"))?;
 
-        self.walk(inner, keys)?;
+        self.walk(inner, keys, &join_path(prefix, head), env)?;
+
+        Ok(())
+    }
+
+    /// Track the variables set in `table`, which is at `prefix`, after those
+    /// of any tables it extends. `extending` holds the tables we're in the
+    /// middle of tracking, to catch cycles.
+    fn track(
+        &mut self,
+        table: &'a Table,
+        prefix: &str,
+        extending: &mut Vec<String>,
+    ) -> eyre::Result<()> {
+        for value in table.values() {
+            let Value::Extends(paths) = value else {
+                continue;
+            };
+            for path in paths {
+                if extending.contains(path) {
+                    let cycle = extending.iter().chain([path]).join(" -> ");
+                    return Err(eyre!("Tables extend each other in a cycle: {cycle}"));
+                }
+                let parent = path
+                    .split('.')
+                    .try_fold(self.root, |table, key| table.get(key)?.as_table())
+                    .ok_or_else(|| eyre!("No table '{path}' for '{prefix}' to extend"))?;
 
+                extending.push(path.clone());
+                self.track(parent, path, extending)?;
+                extending.pop();
+            }
+        }
+
+        // Hooks and aliases are the same however many times we see them, but
+        // variables may not be, such as those that add to `PATH`.
+        let first = !self.tracked.contains(prefix);
+        self.walking_tracked.insert(prefix.to_string());
+        for (var, value) in table {
+            let path = join_path(prefix, var);
+            match value {
+                Value::Hooks(hooks) => {
+                    self.walking_enter = hooks.enter.as_ref().or(self.walking_enter);
+                    self.walking_exit = hooks.exit.as_ref().or(self.walking_exit);
+                }
+                Value::Aliases(aliases) => {
+                    for (name, command) in aliases {
+                        self.aliases.insert(name, command);
+                    }
+                }
+                // Nothing in these is exported.
+                Value::Table(_) | Value::Extends(_) | Value::Meta(_) => (),
+                _ if !first => (),
+                Value::Extract(source) => {
+                    for (var, field) in &source.fields {
+                        let layer = Layer::Field {
+                            source,
+                            path: field,
+                        };
+                        self.push(var, layer, path.clone());
+                    }
+                }
+                value => self.push(var, Layer::Value(value), path),
+            }
+        }
         Ok(())
     }
 }
+
+/// Combines the layers of each variable into its final value, expanding
+/// references between variables, and taking care to detect cycles.
+struct Resolver<'w, 'a> {
+    layers: &'w IndexMap<&'a str, Vec<Layer<'a>>>,
+    current_env: &'w CurrentEnv,
+    /// The contents of each `ExtractSource` we've loaded, so that we only run
+    /// each command once.
+    documents: HashMap<*const ExtractSource, serde_json::Value>,
+    /// The values we've fetched from each provider.
+    provided: HashMap<&'a str, HashMap<String, String>>,
+    /// The variables we've already resolved.
+    done: HashMap<String, Resolved>,
+    /// The variables we're in the middle of resolving.
+    stack: Vec<String>,
+}
+
+impl<'a> Resolver<'_, 'a> {
+    fn resolve(&mut self, var: &str) -> eyre::Result<Resolved> {
+        if let Some(resolved) = self.done.get(var) {
+            return Ok(resolved.clone());
+        }
+
+        self.stack.push(var.to_string());
+        let resolved = self
+            .combine(var)
+            .wrap_err_with(|| eyre!("Failed to resolve '{var}'"));
+        self.stack.pop();
+
+        let resolved = resolved?;
+        self.done.insert(var.to_string(), resolved.clone());
+        Ok(resolved)
+    }
+
+    fn combine(&mut self, var: &str) -> eyre::Result<Resolved> {
+        let layers = self.layers.get(var).map(Vec::as_slice).unwrap_or_default();
+
+        // Anything before the last value that isn't a splice is overridden, so
+        // we skip it entirely.
+        let start = layers.iter().rposition(|layer| layer.as_splice().is_none());
+        let mut resolved = match start.map(|idx| layers[idx]) {
+            Some(Layer::Value(Value::String(value))) => Resolved::Set(self.expand(value)?),
+            Some(Layer::Value(Value::File(source))) => Resolved::Set(source.read()?),
+            Some(Layer::Value(Value::Command(source))) => {
+                Resolved::Set(source.read(self.current_env.restored_vars())?)
+            }
+            Some(Layer::Value(Value::Prompt(source))) => Resolved::Set(source.read()?),
+            Some(Layer::Value(Value::Age(source))) => Resolved::Set(source.read()?),
+            Some(Layer::Value(Value::Provider(source))) => Resolved::Set(self.provided(source)?),
+            Some(Layer::Value(Value::Unset)) => Resolved::Unset,
+            Some(Layer::Value(Value::Generated(value))) => Resolved::Set(value.read()?),
+            Some(Layer::Field { source, path }) => {
+                Resolved::Set(source::extract(self.document(source)?, path)?)
+            }
+            Some(Layer::Value(
+                Value::Splice(_)
+                | Value::Table(_)
+                | Value::Extract(_)
+                | Value::Extends(_)
+                | Value::Meta(_)
+                | Value::Hooks(_)
+                | Value::Aliases(_),
+            )) => {
+                unreachable!()
+            }
+            None => Resolved::Splice {
+                prepend: Vec::new(),
+                append: Vec::new(),
+                separator: layers
+                    .iter()
+                    .find_map(|layer| Some(layer.as_splice()?.separator.clone()))
+                    .unwrap_or_default(),
+            },
+        };
+
+        let splices = layers[start.map_or(0, |idx| idx + 1)..]
+            .iter()
+            .filter_map(Layer::as_splice);
+        for splice in splices {
+            let prepend = self.expand_all(&splice.prepend)?;
+            let append = self.expand_all(&splice.append)?;
+            resolved = resolved.splice(splice, prepend, append)?;
+        }
+
+        Ok(resolved)
+    }
+
+    fn document(&mut self, source: &ExtractSource) -> eyre::Result<&serde_json::Value> {
+        let document = match self.documents.entry(source) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(source.load(self.current_env.restored_vars())?),
+        };
+        Ok(document)
+    }
+
+    /// Get the value for `source`. The first time we need a value from a
+    /// provider, we fetch every value we'll need from it at once.
+    fn provided(&mut self, source: &'a ProviderSource) -> eyre::Result<String> {
+        let name = source.provider.as_str();
+        let values = match self.provided.entry(name) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let refs = self
+                    .layers
+                    .values()
+                    .filter_map(|layers| {
+                        match layers.iter().rfind(|layer| layer.as_splice().is_none()) {
+                            Some(Layer::Value(Value::Provider(other)))
+                                if other.provider == name =>
+                            {
+                                Some(other.reference.as_str())
+                            }
+                            _ => None,
+                        }
+                    })
+                    .unique()
+                    .collect::<Vec<_>>();
+                let vars = self.current_env.restored_vars();
+                entry.insert(provider::fetch(name, &refs, vars)?)
+            }
+        };
+        Ok(values[&source.reference].clone())
+    }
+
+    fn expand(&mut self, template: &str) -> eyre::Result<String> {
+        interpolate::expand(template, &mut |var| self.value(var))
+    }
+
+    fn expand_all(&mut self, templates: &[String]) -> eyre::Result<Vec<String>> {
+        templates
+            .iter()
+            .map(|template| self.expand(template))
+            .collect()
+    }
+
+    /// The final value of `var`, or `None` if it won't be set.
+    fn value(&mut self, var: &str) -> eyre::Result<Option<String>> {
+        let base = self.current_env.base_value(var);
+        // A variable that refers to itself gets the value it would otherwise
+        // have, as with `PATH = "${PATH}:/opt/bin"`.
+        if self.stack.last().is_some_and(|last| last == var) {
+            return Ok(base);
+        }
+        if let Some(idx) = self.stack.iter().position(|other| other == var) {
+            let cycle = self.stack[idx..]
+                .iter()
+                .chain([&var.to_string()])
+                .join(" -> ");
+            return Err(eyre!("Variable references form a cycle: {cycle}"));
+        }
+
+        if !self.layers.contains_key(var) {
+            return Ok(base);
+        }
+        Ok(self.resolve(var)?.apply(base.as_deref()))
+    }
+}
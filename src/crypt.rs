@@ -0,0 +1,211 @@
+//! Encrypting values in the config file with [age](https://age-encryption.org),
+//! so that it can be committed without leaking secrets.
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use age::{
+    Decryptor, Encryptor, Identity, IdentityFile, Recipient,
+    armor::{ArmoredReader, ArmoredWriter, Format},
+};
+use eyre::{Context, eyre};
+use toml_edit::{DocumentMut, InlineTable, Item, TableLike};
+
+use crate::source::AgeSource;
+
+/// The variable holding the path to the identity file used to decrypt values.
+pub const IDENTITY_VAR: &str = "ENVSWITCH_AGE_IDENTITY";
+
+/// The key that marks a table as an encrypted value.
+pub const AGE_KEY: &str = "age";
+
+fn identity_file() -> eyre::Result<IdentityFile<age::NoCallbacks>> {
+    let path = env::var(IDENTITY_VAR).map_err(|_| {
+        eyre!("{IDENTITY_VAR} is not set; set it to the path of your age identity file")
+    })?;
+    IdentityFile::from_file(path.clone())
+        .wrap_err_with(|| eyre!("Failed to read age identity file {path}"))
+}
+
+/// Load the identities to decrypt with from `$ENVSWITCH_AGE_IDENTITY`.
+pub fn identities() -> eyre::Result<Vec<Box<dyn Identity>>> {
+    Ok(identity_file()?.into_identities()?)
+}
+
+/// Parse the recipients to encrypt to from the given public keys and files of
+/// them. If there are none, we encrypt to the identities in
+/// `$ENVSWITCH_AGE_IDENTITY`.
+pub fn recipients(
+    keys: &[String],
+    files: &[PathBuf],
+) -> eyre::Result<Vec<Box<dyn Recipient + Send>>> {
+    let mut recipients: Vec<Box<dyn Recipient + Send>> = Vec::new();
+    for key in keys {
+        recipients.push(Box::new(parse_recipient(key)?));
+    }
+    for file in files {
+        recipients.extend(
+            read_recipients_file(file)
+                .wrap_err_with(|| eyre!("Failed to read recipients file {}", file.display()))?,
+        );
+    }
+
+    if recipients.is_empty() {
+        recipients = identity_file()?.to_recipients()?;
+    }
+    Ok(recipients)
+}
+
+/// Read public keys from `file`, one per line, ignoring blank lines and
+/// comments as age does.
+fn read_recipients_file(file: &Path) -> eyre::Result<Vec<Box<dyn Recipient + Send>>> {
+    let mut recipients: Vec<Box<dyn Recipient + Send>> = Vec::new();
+    for line in BufReader::new(fs::File::open(file)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            recipients.push(Box::new(parse_recipient(line)?));
+        }
+    }
+    Ok(recipients)
+}
+
+fn parse_recipient(key: &str) -> eyre::Result<age::x25519::Recipient> {
+    age::x25519::Recipient::from_str(key).map_err(|err| eyre!("Invalid recipient '{key}': {err}"))
+}
+
+pub fn decrypt(ciphertext: &str, identities: &[Box<dyn Identity>]) -> eyre::Result<String> {
+    String::from_utf8(decrypt_bytes(ciphertext, identities)?)
+        .wrap_err("Decrypted value is not valid UTF-8")
+}
+
+pub fn decrypt_bytes(ciphertext: &str, identities: &[Box<dyn Identity>]) -> eyre::Result<Vec<u8>> {
+    let decryptor = Decryptor::new(ArmoredReader::new(ciphertext.as_bytes()))?;
+    let mut reader = decryptor.decrypt(identities.iter().map(|identity| identity.as_ref()))?;
+    let mut plaintext = Vec::new();
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+pub fn encrypt(plaintext: &str, recipients: &[Box<dyn Recipient + Send>]) -> eyre::Result<String> {
+    let encryptor = Encryptor::with_recipients(
+        recipients
+            .iter()
+            .map(|recipient| recipient.as_ref() as &dyn Recipient),
+    )?;
+    let mut ciphertext = Vec::new();
+    let armor = ArmoredWriter::wrap_output(&mut ciphertext, Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(armor)?;
+    writer.write_all(plaintext.as_bytes())?;
+    writer.finish()?.finish()?;
+    Ok(String::from_utf8(ciphertext)?)
+}
+
+fn read_document(file: &Path) -> eyre::Result<DocumentMut> {
+    fs::read_to_string(file)
+        .wrap_err_with(|| eyre!("Failed to read file {}", file.display()))?
+        .parse()
+        .wrap_err_with(|| eyre!("Failed to parse {}", file.display()))
+}
+
+fn write_document(file: &Path, document: &DocumentMut) -> eyre::Result<()> {
+    fs::write(file, document.to_string())
+        .wrap_err_with(|| eyre!("Failed to write file {}", file.display()))
+}
+
+/// Encrypt the value of `var` in the environment `env` of `file`, rewriting it
+/// in place.
+pub fn encrypt_var(
+    file: &Path,
+    env: &str,
+    var: &str,
+    recipients: &[Box<dyn Recipient + Send>],
+) -> eyre::Result<()> {
+    let mut document = read_document(file)?;
+
+    let mut table: &mut dyn TableLike = document.as_table_mut();
+    for key in env.split('.').filter(|key| !key.is_empty()) {
+        table = table
+            .get_mut(key)
+            .and_then(Item::as_table_like_mut)
+            .ok_or_else(|| eyre!("No environment '{env}' in {}", file.display()))?;
+    }
+    let item = table
+        .get_mut(var)
+        .ok_or_else(|| eyre!("No variable '{var}' in '{env}'"))?;
+
+    let plaintext = match item.as_str() {
+        Some(plaintext) => plaintext,
+        None if is_encrypted(item) => return Err(eyre!("'{var}' is already encrypted")),
+        None => return Err(eyre!("Only string values can be encrypted")),
+    };
+    let mut encrypted = InlineTable::new();
+    encrypted.insert(AGE_KEY, encrypt(plaintext, recipients)?.into());
+    // Keep any comment after the value.
+    if let Some(value) = item.as_value() {
+        *encrypted.decor_mut() = value.decor().clone();
+    }
+    *item = toml_edit::value(encrypted);
+
+    write_document(file, &document)
+}
+
+/// Decrypt every encrypted value in `file` and encrypt it again for
+/// `recipients`, rewriting the file in place. Returns how many values were
+/// re-encrypted.
+pub fn rekey(file: &Path, recipients: &[Box<dyn Recipient + Send>]) -> eyre::Result<usize> {
+    fn rekey_table(
+        table: &mut dyn TableLike,
+        prefix: &str,
+        identities: &[Box<dyn Identity>],
+        recipients: &[Box<dyn Recipient + Send>],
+    ) -> eyre::Result<usize> {
+        let mut count = 0;
+        for (key, item) in table.iter_mut() {
+            let path = if prefix.is_empty() {
+                key.to_string()
+            } else {
+                format!("{prefix}.{key}")
+            };
+            let Some(table) = item.as_table_like_mut() else {
+                continue;
+            };
+            let encrypted = is_encrypted_table(table);
+            match table.get_mut(AGE_KEY) {
+                Some(age) if encrypted => {
+                    let rekeyed = age
+                        .as_str()
+                        .ok_or_else(|| eyre!("expected a string"))
+                        .and_then(|ciphertext| decrypt(ciphertext, identities))
+                        .and_then(|plaintext| encrypt(&plaintext, recipients))
+                        .wrap_err_with(|| eyre!("Failed to re-encrypt '{path}'"))?;
+                    *age = toml_edit::value(rekeyed);
+                    count += 1;
+                }
+                _ => count += rekey_table(table, &path, identities, recipients)?,
+            }
+        }
+        Ok(count)
+    }
+
+    let identities = identities()?;
+    let mut document = read_document(file)?;
+    let count = rekey_table(document.as_table_mut(), "", &identities, recipients)?;
+    write_document(file, &document)?;
+    Ok(count)
+}
+
+fn is_encrypted(item: &Item) -> bool {
+    item.as_table_like().is_some_and(is_encrypted_table)
+}
+
+/// Whether a table is an encrypted value, rather than an environment that
+/// happens to set a variable named `age`.
+fn is_encrypted_table(table: &dyn TableLike) -> bool {
+    let keys = table.iter().map(|(key, _)| key).collect::<Vec<_>>();
+    AgeSource::matches_keys(&keys)
+}
@@ -1,65 +1,405 @@
 use std::env;
 
-use eyre::eyre;
+use eyre::{Context, eyre};
 use itertools::Itertools;
 
-use crate::shell::Shell;
+use crate::{
+    config::{check_alias_name, check_var_name},
+    config_walker::Resolved,
+    hooks::{Hook, Run},
+    shell::Shell,
+};
 
 pub const ENVSWITCH_VAR: &str = "ENVSWITCH_ENV";
 
+/// The variable we keep the state of `namespace` in, or of the default
+/// namespace if it's `None`.
+pub fn state_var(namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("{ENVSWITCH_VAR}_{namespace}"),
+        None => ENVSWITCH_VAR.to_string(),
+    }
+}
+
+/// Check that `namespace` can be part of a variable name.
+pub fn check_namespace(namespace: &str) -> eyre::Result<()> {
+    let valid = !namespace.is_empty()
+        && namespace
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "invalid namespace '{namespace}'; use only letters, digits, and '_'"
+        ))
+    }
+}
+
+/// Characters that have meaning in the value of `ENVSWITCH_VAR`, and so must be
+/// escaped when they appear in a saved value.
+const RESERVED_CHARS: &[char] = &['%', ',', ':', '=', '+'];
+
 pub struct CurrentEnv {
-    vars: Vec<String>,
+    namespace: Option<String>,
+    /// The variable we keep our state in.
+    state_var: String,
+    vars: Vec<Tracked>,
+    /// The aliases we have defined.
+    aliases: Vec<String>,
+    /// The hooks to run on leaving, recorded on entering so that we run them
+    /// even if we've since moved to a different config.
+    exit: Vec<Hook>,
+}
+
+/// A variable that we have set, along with how to undo it.
+struct Tracked {
+    var: String,
+    restore: Restore,
+}
+
+enum Restore {
+    /// The variable did not exist before we set it.
+    Unset,
+    /// The value the variable had before we set it.
+    Value(String),
+    /// We added these segments to the start and end of the variable; anything
+    /// else in it is not ours.
+    Segments {
+        separator: String,
+        prepend: Vec<String>,
+        append: Vec<String>,
+    },
+}
+
+/// What each recorded segment starts with, to say which end we added it to.
+const PREPEND_MARKER: char = '<';
+const APPEND_MARKER: char = '>';
+
+impl Tracked {
+    fn parse(entry: &str) -> eyre::Result<Self> {
+        let (var, restore) = match entry.find(['=', '+']) {
+            Some(idx) if entry[idx..].starts_with('=') => {
+                (&entry[..idx], Restore::Value(decode(&entry[idx + 1..])?))
+            }
+            Some(idx) => {
+                let mut parts = entry[idx + 1..].split('+').map(decode);
+                let separator = parts.next().ok_or_else(|| eyre!("missing separator"))??;
+                let (mut prepend, mut append) = (Vec::new(), Vec::new());
+                for part in parts {
+                    let part = part?;
+                    if let Some(segment) = part.strip_prefix(PREPEND_MARKER) {
+                        prepend.push(segment.to_string());
+                    } else if let Some(segment) = part.strip_prefix(APPEND_MARKER) {
+                        append.push(segment.to_string());
+                    } else {
+                        return Err(eyre!("segment '{part}' has no marker"));
+                    }
+                }
+                (
+                    &entry[..idx],
+                    Restore::Segments {
+                        separator,
+                        prepend,
+                        append,
+                    },
+                )
+            }
+            None => (entry, Restore::Unset),
+        };
+        check_var_name(var)?;
+
+        Ok(Tracked {
+            var: var.to_string(),
+            restore,
+        })
+    }
+
+    /// The value this variable will have once we have undone our changes.
+    fn restored(&self) -> Option<String> {
+        match &self.restore {
+            Restore::Unset => None,
+            Restore::Value(value) => Some(value.clone()),
+            Restore::Segments {
+                separator,
+                prepend,
+                append,
+            } => {
+                // The user may have changed the variable since we set it, so
+                // we take care to only remove what we added, from the end we
+                // added it to, in case the same segment was already there.
+                let current = env::var(&self.var).ok()?;
+                let mut parts = current.split(separator.as_str()).collect::<Vec<_>>();
+                for segment in prepend {
+                    if let Some(idx) = parts.iter().position(|part| part == segment) {
+                        parts.remove(idx);
+                    }
+                }
+                for segment in append {
+                    if let Some(idx) = parts.iter().rposition(|part| part == segment) {
+                        parts.remove(idx);
+                    }
+                }
+                let value = parts.join(separator);
+                (!value.is_empty()).then_some(value)
+            }
+        }
+    }
 }
 
 impl CurrentEnv {
-    pub fn name() -> String {
-        match env::var(ENVSWITCH_VAR) {
-            Ok(value) => value
-                .split_once(':')
-                .map(|(env, _)| env.to_string())
-                .unwrap_or_default(),
-            Err(_) => String::new(),
+    pub fn name(namespace: Option<&str>) -> String {
+        Self::try_name(namespace).unwrap_or_default()
+    }
+
+    /// The name of the current environment, or `None` if we haven't set one.
+    /// Unlike with `name`, this distinguishes having set only global
+    /// variables from not having set anything.
+    pub fn try_name(namespace: Option<&str>) -> Option<String> {
+        let value = env::var(state_var(namespace)).ok()?;
+        let (env, _) = value.split_once(':')?;
+        Some(env.to_string())
+    }
+
+    /// The name of the current environment in each namespace that has one,
+    /// starting with the default namespace.
+    pub fn namespaces() -> Vec<(Option<String>, String)> {
+        all_namespaces()
+            .filter_map(|namespace| {
+                let name = Self::name(namespace.as_deref());
+                (!name.is_empty()).then_some((namespace, name))
+            })
+            .collect()
+    }
+
+    /// The value `var` had before any namespace set it, such as for reading
+    /// the config files of other tools, which we may have pointed elsewhere.
+    pub fn original_value(var: &str) -> Option<String> {
+        let tracking = all_namespaces().find_map(|namespace| {
+            let current = Self::new(namespace.as_deref()).ok()?;
+            current
+                .vars
+                .iter()
+                .any(|tracked| tracked.var == var)
+                .then_some(current)
+        });
+        match tracking {
+            Some(current) => current.base_value(var),
+            None => env::var(var).ok(),
         }
     }
 
-    pub fn new() -> eyre::Result<Self> {
-        match env::var(ENVSWITCH_VAR) {
+    pub fn new(namespace: Option<&str>) -> eyre::Result<Self> {
+        let state_var = state_var(namespace);
+        match env::var(&state_var) {
             Ok(value) => {
-                let Some((_env_name, vars)) = value.split_once(':') else {
-                    return Err(eyre!(
-                        "Invalid {ENVSWITCH_VAR} variable; please inspect and clear it"
-                    ));
+                let invalid = || eyre!("Invalid {state_var} variable; please inspect and clear it");
+
+                // The aliases and exit hooks are only recorded if there are
+                // any.
+                let mut parts = value.splitn(4, ':');
+                let (Some(_env_name), Some(vars)) = (parts.next(), parts.next()) else {
+                    return Err(invalid());
                 };
 
+                let vars = vars
+                    .split(',')
+                    .filter(|entry| !entry.is_empty())
+                    .map(Tracked::parse)
+                    .collect::<eyre::Result<_>>()
+                    .wrap_err_with(invalid)?;
+                let aliases = parts
+                    .next()
+                    .into_iter()
+                    .flat_map(|aliases| aliases.split(','))
+                    .filter(|alias| !alias.is_empty())
+                    .map(|alias| check_alias_name(alias).map(|()| alias.to_string()))
+                    .collect::<eyre::Result<_>>()
+                    .wrap_err_with(invalid)?;
+                let exit = parts
+                    .next()
+                    .into_iter()
+                    .flat_map(|hooks| hooks.split(','))
+                    .filter(|hook| !hook.is_empty())
+                    .map(parse_hook)
+                    .collect::<eyre::Result<_>>()
+                    .wrap_err_with(invalid)?;
+
                 Ok(Self {
-                    vars: vars.split(',').map(ToString::to_string).collect(),
+                    namespace: namespace.map(str::to_string),
+                    state_var,
+                    vars,
+                    aliases,
+                    exit,
                 })
             }
-            Err(_) => Ok(Self { vars: Vec::new() }),
+            Err(_) => Ok(Self {
+                namespace: namespace.map(str::to_string),
+                state_var,
+                vars: Vec::new(),
+                aliases: Vec::new(),
+                exit: Vec::new(),
+            }),
         }
     }
 
+    /// The hooks to run on leaving the current environment.
+    pub fn exit_hooks(&self) -> &[Hook] {
+        &self.exit
+    }
+
+    /// Commands to undo everything we have set, restoring any variables we
+    /// shadowed to their previous values.
     pub fn clear_commands(&self, shell: &Shell) -> impl Iterator<Item = String> {
+        let vars = self.vars.iter().map(|tracked| match tracked.restored() {
+            Some(value) => shell.set_var(&tracked.var, &value),
+            None => shell.clear_var(&tracked.var),
+        });
+        let aliases = self
+            .aliases
+            .iter()
+            .map(|alias| shell.clear_alias(alias, self.namespace.as_deref()));
+        vars.chain(aliases)
+    }
+
+    /// Each variable we have set, with the value it will have once our clear
+    /// commands have run.
+    pub fn restored_vars(&self) -> impl Iterator<Item = (&str, Option<String>)> {
         self.vars
             .iter()
-            .filter(|var| !var.is_empty())
-            .map(|var| shell.clear_var(var))
+            .map(|tracked| (tracked.var.as_str(), tracked.restored()))
+    }
+
+    /// The value `var` will have once our clear commands have run.
+    pub fn base_value(&self, var: &str) -> Option<String> {
+        match self.vars.iter().find(|tracked| tracked.var == var) {
+            Some(tracked) => tracked.restored(),
+            None => env::var(var).ok(),
+        }
     }
 
     pub fn set<'a>(
         &self,
         shell: &Shell,
-        env: &'a str,
-        vars: impl Iterator<Item = &'a str>,
+        env: &str,
+        vars: impl Iterator<Item = (&'a str, &'a Resolved)>,
+        mut aliases: impl Iterator<Item = &'a str>,
+        exit: &[&Hook],
     ) -> String {
         let mut value = String::new();
         value.push_str(env);
         value.push(':');
 
-        for s in Itertools::intersperse(vars, ",") {
-            value.push_str(s);
+        let entries = vars.filter_map(|(var, resolved)| match resolved {
+            Resolved::Set(_) => match self.base_value(var) {
+                Some(previous) => Some(format!("{var}={}", encode(&previous))),
+                None => Some(var.to_string()),
+            },
+            // If we unset a variable, we only need to track it if it was set.
+            Resolved::Unset => self
+                .base_value(var)
+                .map(|previous| format!("{var}={}", encode(&previous))),
+            Resolved::Splice {
+                prepend,
+                append,
+                separator,
+            } => {
+                // We record each part separately, along with which end it's
+                // at, so that we can find them again when removing them.
+                let mark = |marker: char, segments: &'a [String]| {
+                    segments
+                        .iter()
+                        .flat_map(|segment| segment.split(separator))
+                        .map(move |segment| format!("{marker}{}", encode(segment)))
+                };
+                let segments = mark(PREPEND_MARKER, prepend).chain(mark(APPEND_MARKER, append));
+                let entry = [var.to_string(), encode(separator)]
+                    .into_iter()
+                    .chain(segments)
+                    .join("+");
+                Some(entry)
+            }
+        });
+        for s in Itertools::intersperse(entries, ",".to_string()) {
+            value.push_str(&s);
+        }
+
+        // The aliases and exit hooks are only recorded if there are any.
+        let aliases = aliases.join(",");
+        if !aliases.is_empty() || !exit.is_empty() {
+            value.push(':');
+            value.push_str(&aliases);
+        }
+        if !exit.is_empty() {
+            value.push(':');
+            value.push_str(&exit.iter().map(|hook| encode_hook(hook)).join(","));
+        }
+
+        shell.set_var(&self.state_var, &value)
+    }
+}
+
+/// The default namespace, followed by each other namespace that has state.
+fn all_namespaces() -> impl Iterator<Item = Option<String>> {
+    let prefix = format!("{ENVSWITCH_VAR}_");
+    let namespaces = env::vars()
+        .filter_map(|(var, _)| {
+            let namespace = var.strip_prefix(&prefix)?;
+            check_namespace(namespace).ok()?;
+            Some(namespace.to_string())
+        })
+        .sorted()
+        .map(Some);
+    [None].into_iter().chain(namespaces)
+}
+
+/// Write `hook` as its command, preceded by where it runs.
+fn encode_hook(hook: &Hook) -> String {
+    let run = match hook.run() {
+        Run::Shell => 's',
+        Run::Direct => 'd',
+    };
+    format!("{run}{}", encode(hook.command()))
+}
+
+/// Reverse `encode_hook`.
+fn parse_hook(entry: &str) -> eyre::Result<Hook> {
+    let run = match entry.chars().next() {
+        Some('s') => Run::Shell,
+        Some('d') => Run::Direct,
+        _ => return Err(eyre!("invalid hook")),
+    };
+    Ok(Hook::new(decode(&entry[1..])?, run))
+}
+
+/// Percent-encode any reserved characters in `value`.
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if RESERVED_CHARS.contains(&ch) {
+            encoded.push_str(&format!("%{:02X}", ch as u8));
+        } else {
+            encoded.push(ch);
         }
+    }
+    encoded
+}
 
-        shell.set_var(ENVSWITCH_VAR, &value)
+/// Reverse `encode`.
+fn decode(value: &str) -> eyre::Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [
+                iter.next().ok_or_else(|| eyre!("truncated escape"))?,
+                iter.next().ok_or_else(|| eyre!("truncated escape"))?,
+            ];
+            let hex = str::from_utf8(&hex)?;
+            bytes.push(u8::from_str_radix(hex, 16).wrap_err("invalid escape")?);
+        } else {
+            bytes.push(byte);
+        }
     }
+    Ok(String::from_utf8(bytes)?)
 }
@@ -0,0 +1,213 @@
+//! Environments generated from the config files of other tools, such as one per
+//! AWS profile.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, eyre};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::current_env::CurrentEnv;
+
+/// The environments to generate, each with the variables it sets.
+pub type Generated = IndexMap<String, IndexMap<&'static str, GeneratedValue>>;
+
+/// The value of a variable in a generated environment. Unlike one written in
+/// the config, it is used as it is, as it comes from another tool's config
+/// where `$` means nothing to us.
+#[derive(Debug)]
+pub enum GeneratedValue {
+    String(String),
+    /// `KUBECONFIG`, selecting `context` ahead of `paths`. The file that
+    /// selects it is only written once the environment is set, rather than
+    /// whenever the config is loaded.
+    KubeConfig {
+        context: String,
+        paths: Vec<PathBuf>,
+    },
+}
+
+impl GeneratedValue {
+    pub fn read(&self) -> eyre::Result<String> {
+        match self {
+            GeneratedValue::String(value) => Ok(value.clone()),
+            GeneratedValue::KubeConfig { context, paths } => {
+                let selector = kube_context_selector(context)?;
+                let kubeconfig = env::join_paths([&selector].into_iter().chain(paths))
+                    .wrap_err_with(|| eyre!("Invalid path in $KUBECONFIG"))?;
+                Ok(kubeconfig.to_string_lossy().into_owned())
+            }
+        }
+    }
+}
+
+/// Generate environments with the named generator.
+pub fn generate(generator: &str) -> eyre::Result<Generated> {
+    match generator {
+        "aws-profiles" => aws_profiles(),
+        "kube-contexts" => kube_contexts(),
+        other => Err(eyre!(
+            "unknown generator '{other}'; expected 'aws-profiles' or 'kube-contexts'"
+        )),
+    }
+}
+
+/// Read `path`, treating a missing file as empty, as not everyone uses every
+/// tool.
+fn read_optional(path: &Path) -> eyre::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).wrap_err_with(|| eyre!("Failed to read file {}", path.display())),
+    }
+}
+
+fn home_file(path: &str) -> eyre::Result<PathBuf> {
+    env::home_dir()
+        .map(|home| home.join(path))
+        .ok_or_else(|| eyre!("Could not determine home directory"))
+}
+
+/// One environment per profile in `~/.aws/config` (or `$AWS_CONFIG_FILE`),
+/// setting `AWS_PROFILE`, and `AWS_REGION` if the profile has one.
+fn aws_profiles() -> eyre::Result<Generated> {
+    // An environment may have changed where the config is, but the profiles
+    // come from where it was before.
+    let path = match CurrentEnv::original_value("AWS_CONFIG_FILE") {
+        Some(path) => PathBuf::from(path),
+        None => home_file(".aws/config")?,
+    };
+    let Some(contents) = read_optional(&path)? else {
+        return Ok(Generated::new());
+    };
+
+    let mut profiles = Generated::new();
+    let mut current = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            // Other sections, such as `[sso-session ...]`, aren't profiles.
+            let profile = match section.trim() {
+                "default" => Some("default"),
+                section => section.strip_prefix("profile ").map(str::trim),
+            };
+            current = profile.map(|profile| {
+                profiles.entry(profile.to_string()).or_insert_with(|| {
+                    let profile = GeneratedValue::String(profile.to_string());
+                    IndexMap::from([("AWS_PROFILE", profile)])
+                });
+                profile.to_string()
+            });
+        } else if let Some((key, value)) = line.split_once('=')
+            && key.trim() == "region"
+            && let Some(profile) = &current
+        {
+            let region = GeneratedValue::String(value.trim().to_string());
+            profiles[profile].insert("AWS_REGION", region);
+        }
+    }
+    Ok(profiles)
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeConfig {
+    #[serde(default)]
+    contexts: Vec<KubeContext>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KubeContext {
+    name: String,
+}
+
+/// A kubeconfig that only selects a context. As kubectl uses the first
+/// `current-context` among the files in `$KUBECONFIG`, putting this first
+/// selects the context without changing the user's own files.
+#[derive(Debug, Serialize)]
+struct KubeContextSelector<'a> {
+    #[serde(rename = "apiVersion")]
+    api_version: &'a str,
+    kind: &'a str,
+    #[serde(rename = "current-context")]
+    current_context: &'a str,
+}
+
+/// One environment per context in `~/.kube/config` (or each file in
+/// `$KUBECONFIG`), setting `KUBECONFIG` to those files, preceded by one that
+/// selects the context, and `KUBE_CONTEXT` to its name.
+fn kube_contexts() -> eyre::Result<Generated> {
+    // We read the files from before any environment changed `$KUBECONFIG`, as
+    // ours point to the same ones.
+    let paths = match CurrentEnv::original_value("KUBECONFIG") {
+        Some(paths) if !paths.is_empty() => env::split_paths(&paths).collect::<Vec<_>>(),
+        _ => vec![home_file(".kube/config")?],
+    };
+
+    let mut contexts = Generated::new();
+    for path in &paths {
+        let Some(contents) = read_optional(path)? else {
+            continue;
+        };
+        let config: KubeConfig = serde_yaml::from_str(&contents)
+            .wrap_err_with(|| eyre!("Failed to parse {}", path.display()))?;
+        for context in config.contexts {
+            // As with kubectl, the first file to define a context wins.
+            if contexts.contains_key(&context.name) {
+                continue;
+            }
+            let kubeconfig = GeneratedValue::KubeConfig {
+                context: context.name.clone(),
+                paths: paths.clone(),
+            };
+            contexts.insert(
+                context.name.clone(),
+                IndexMap::from([
+                    ("KUBECONFIG", kubeconfig),
+                    ("KUBE_CONTEXT", GeneratedValue::String(context.name)),
+                ]),
+            );
+        }
+    }
+    Ok(contexts)
+}
+
+/// The directory we keep files in that must outlive the cache, as something
+/// else still uses them: `$XDG_STATE_HOME/envswitch`, falling back to
+/// `~/.local/state/envswitch`.
+fn state_dir() -> eyre::Result<PathBuf> {
+    let base = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_file(".local/state")?,
+    };
+    Ok(base.join("envswitch"))
+}
+
+/// The path to a kubeconfig that selects `context`, which we write to our
+/// state directory if it isn't there already. It isn't cached, as kubectl
+/// skips a missing file in `$KUBECONFIG`, and would quietly use whatever
+/// context the user's own files select instead.
+fn kube_context_selector(context: &str) -> eyre::Result<PathBuf> {
+    let contents = serde_yaml::to_string(&KubeContextSelector {
+        api_version: "v1",
+        kind: "Config",
+        current_context: context,
+    })?;
+    // Context names may have any characters, so we name the file by a hash.
+    let name = format!("{:x}.yaml", Sha256::digest(context.as_bytes()));
+    let path = state_dir()?.join("kube-contexts").join(name);
+    if read_optional(&path)?.as_deref() != Some(&contents) {
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| eyre!("Failed to create directory {}", dir.display()))?;
+        fs::write(&path, contents)
+            .wrap_err_with(|| eyre!("Failed to write file {}", path.display()))?;
+    }
+    Ok(path)
+}
@@ -0,0 +1,114 @@
+//! Commands to run when entering or leaving an environment, such as to switch
+//! `kubectl` contexts.
+
+use std::{
+    io,
+    process::{self, Stdio},
+};
+
+use eyre::{Context, eyre};
+use serde::Deserialize;
+
+use crate::source;
+
+/// The hooks set in a table, each of which is inherited by the tables nested in
+/// it unless they set their own.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    pub enter: Option<Hook>,
+    pub exit: Option<Hook>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(from = "RawHook")]
+pub struct Hook {
+    command: String,
+    run: Run,
+}
+
+/// Where to run a hook.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Run {
+    /// Emit the command for the user's shell to run, along with the commands
+    /// that set variables, so that it can change the shell itself.
+    #[default]
+    Shell,
+    /// Run the command with `sh` ourselves, which works the same in every
+    /// shell.
+    Direct,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged, expecting = "a string or a table with `command`")]
+enum RawHook {
+    Command(String),
+    Table(HookTable),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookTable {
+    command: String,
+    #[serde(default)]
+    run: Run,
+}
+
+impl From<RawHook> for Hook {
+    fn from(raw: RawHook) -> Self {
+        match raw {
+            RawHook::Command(command) => Hook {
+                command,
+                run: Run::Shell,
+            },
+            RawHook::Table(HookTable { command, run }) => Hook { command, run },
+        }
+    }
+}
+
+impl Hook {
+    pub fn new(command: String, run: Run) -> Self {
+        Hook { command, run }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn run(&self) -> Run {
+        self.run
+    }
+
+    /// The command for the shell to run, if it should run there.
+    pub fn shell_command(&self) -> Option<&str> {
+        (self.run == Run::Shell).then_some(self.command.as_str())
+    }
+
+    /// Run the hook now if it should be run directly, with `vars` changed in
+    /// its environment; `None` removes a variable. A hook that fails only
+    /// warns, so that it can't stop the environment from changing.
+    pub fn run_direct<'a>(&self, vars: impl Iterator<Item = (&'a str, Option<String>)>) {
+        if self.run != Run::Direct {
+            return;
+        }
+
+        let mut command = process::Command::new("sh");
+        command.arg("-c").arg(&self.command);
+        source::set_env(&mut command, vars);
+        // Our stdout is run by the shell, so the hook's output goes to stderr.
+        let result = command
+            .stdin(Stdio::inherit())
+            .stdout(io::stderr())
+            .stderr(Stdio::inherit())
+            .status()
+            .wrap_err_with(|| eyre!("Failed to run hook `{}`", self.command))
+            .and_then(|status| match status.success() {
+                true => Ok(()),
+                false => Err(eyre!("Hook `{}` failed with {status}", self.command)),
+            });
+        if let Err(err) = result {
+            eprintln!("Warning: {err:#}");
+        }
+    }
+}
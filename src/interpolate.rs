@@ -0,0 +1,72 @@
+use eyre::eyre;
+
+use crate::config::check_var_name;
+
+/// Expand `${VAR}` and `${VAR:-default}` references in `template`, looking up
+/// variables with `lookup`. `$$` is a literal `$`, as is a `$` that isn't
+/// followed by `{`.
+///
+/// As in the shell, the default is used if the variable is unset or empty. It
+/// may itself contain references.
+pub fn expand(
+    template: &str,
+    lookup: &mut impl FnMut(&str) -> eyre::Result<Option<String>>,
+) -> eyre::Result<String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end =
+                closing_brace(after).ok_or_else(|| eyre!("unterminated '${{' in '{template}'"))?;
+            let reference = &after[..end];
+            rest = &after[end + 1..];
+
+            let (var, default) = match reference.split_once(":-") {
+                Some((var, default)) => (var, Some(default)),
+                None => (reference, None),
+            };
+            check_var_name(var)
+                .map_err(|err| eyre!("invalid reference '${{{reference}}}': {err}"))?;
+
+            match (lookup(var)?, default) {
+                (Some(value), None) => expanded.push_str(&value),
+                (Some(value), Some(_)) if !value.is_empty() => expanded.push_str(&value),
+                (_, Some(default)) => expanded.push_str(&expand(default, lookup)?),
+                (None, None) => {
+                    return Err(eyre!(
+                        "'{var}' is not defined; use '${{{var}:-default}}' to give it a default"
+                    ));
+                }
+            }
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Find the `}` that closes a reference, skipping over any nested ones.
+fn closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((idx, ch)) = chars.next() {
+        match ch {
+            '$' if chars.next_if(|&(_, ch)| ch == '$').is_some() => (),
+            '$' if chars.next_if(|&(_, ch)| ch == '{').is_some() => depth += 1,
+            '}' if depth == 0 => return Some(idx),
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
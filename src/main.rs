@@ -1,31 +1,67 @@
-use std::borrow::Cow;
+use std::io::{self, IsTerminal};
 
 use clap::Parser;
 use color_eyre::config::HookBuilder;
+use eyre::{Context, eyre};
+use itertools::Itertools;
 
 use crate::{
-    cli::{Cli, Commands, Complete, Set, Setup},
-    config::{Key, deep_keys},
+    cli::{Cache, CacheCommand, Cli, Commands, Complete, Encrypt, Get, Rekey, Set, Setup},
+    config::{Key, check_alias_name, deep_keys},
     config_walker::ConfigWalker,
-    current_env::CurrentEnv,
+    current_env::{CurrentEnv, check_namespace},
 };
 
+mod cache;
 mod cli;
 mod config;
 mod config_walker;
+mod crypt;
 mod current_env;
+mod generate;
+mod hooks;
+mod interpolate;
+mod provider;
 mod shell;
+mod sops;
+mod source;
+mod tty;
 
 #[cfg(test)]
 mod test;
 
-fn get() -> eyre::Result<()> {
-    let name = CurrentEnv::name();
+fn get(args: Get) -> eyre::Result<()> {
+    let name = if args.all {
+        let namespaces = CurrentEnv::namespaces();
+        for (namespace, name) in &namespaces {
+            match namespace {
+                Some(namespace) => println!("{namespace}: {name}"),
+                None => println!("{name}"),
+            }
+        }
+        namespaces.into_iter().map(|(_, name)| name).join(" ")
+    } else {
+        if let Some(namespace) = &args.namespace {
+            check_namespace(namespace)?;
+        }
+        let name = CurrentEnv::name(args.namespace.as_deref());
+        if !name.is_empty() {
+            println!("{name}");
+        }
+        name
+    };
 
+    if args.verbose {
+        let files = args.config.files()?;
+        for file in &files {
+            println!("Config file: {}", file.display());
+        }
+        if files.is_empty() {
+            println!("Config file: none found");
+        }
+    }
     if name.is_empty() {
         std::process::exit(1);
-    } else {
-        println!("{}", CurrentEnv::name());
     }
     Ok(())
 }
@@ -33,32 +69,116 @@ fn get() -> eyre::Result<()> {
 fn set(args: Set) -> eyre::Result<()> {
     let Set {
         config,
-        env,
+        envs,
         shell,
         list,
+        tag,
+        explain,
+        namespace,
     } = args;
-    let config = cli::load_config_file(config.file.as_deref())?;
+    if let Some(namespace) = &namespace {
+        check_namespace(namespace)?;
+    }
+    let files = config.files()?;
+    let (config, origins) = cli::load_config(&files)?;
     if list {
         eprintln!("Available environments:");
-        for env in deep_keys(&config) {
-            eprintln!("  {env}");
+        let envs = deep_keys(&config)
+            .filter(|env| env.has_tags(&tag))
+            .collect::<Vec<_>>();
+        let width = envs.iter().map(|env| env.name.len()).max().unwrap_or(0);
+        let colored = io::stderr().is_terminal();
+        for env in envs {
+            let name = match env.meta.and_then(|meta| meta.color) {
+                Some(color) if colored => color.paint(&env.name),
+                _ => env.name.clone(),
+            };
+            match env.description() {
+                Some(description) => {
+                    let padding = " ".repeat(width - env.name.len());
+                    eprintln!("  {name}{padding}  {description}");
+                }
+                None => eprintln!("  {name}"),
+            }
         }
         return Ok(());
     }
 
-    let current_env = CurrentEnv::new()?;
+    let current_env = CurrentEnv::new(namespace.as_deref())?;
 
-    let keys = env
-        .split('.')
-        .map(|k| Key::try_from(k.to_string()))
-        .collect::<eyre::Result<Vec<_>>>()?;
+    let env = envs.join("+");
+    let keys = parse_env(&env)?;
+    let mut walker = ConfigWalker::new(&config, &keys)?;
+    if explain {
+        if env.is_empty() {
+            eprintln!("Global variables:");
+        } else {
+            eprintln!("Variables for {env}:");
+        }
+        let sources = walker.sources().map(|(var, paths)| {
+            let files = paths
+                .iter()
+                .filter_map(|path| origins.get(path))
+                .unique()
+                .map(|file| file.display())
+                .join(", ");
+            (var, files)
+        });
+        let sources = sources.collect::<Vec<_>>();
+        let width = sources.iter().map(|(var, _)| var.len()).max().unwrap_or(0);
+        for (var, files) in sources {
+            eprintln!("  {var:width$}  {files}");
+        }
+        return Ok(());
+    }
+    for (var, (earlier, later)) in &walker.conflicts {
+        eprintln!(
+            "Warning: {var} is set by both {} and {}; using the value from {}",
+            envs_name(&keys[*earlier]),
+            envs_name(&keys[*later]),
+            envs_name(&keys[*later]),
+        );
+    }
+    walker
+        .resolve(&current_env)
+        .wrap_err_with(|| eyre!("Failed to set environment '{env}'"))?;
 
-    let walker = ConfigWalker::new(&config, keys.iter())?;
+    // We run the exit hooks recorded when we entered, as we may have moved to
+    // a config where the environment we're leaving is something else.
+    let exit = current_env.exit_hooks();
+    for hook in exit {
+        hook.run_direct(std::iter::empty());
+    }
+    for hook in &walker.enter {
+        let restored = current_env.restored_vars();
+        let vals = walker
+            .vals
+            .iter()
+            .map(|(var, resolved)| (*var, resolved.apply(current_env.base_value(var).as_deref())));
+        hook.run_direct(restored.chain(vals));
+    }
 
-    let commands = current_env
-        .clear_commands(&shell)
-        .chain([current_env.set(&shell, &env, walker.vals.keys().copied())])
-        .chain(walker.set_commands(&shell));
+    let commands = exit
+        .iter()
+        .filter_map(|hook| hook.shell_command())
+        .map(str::to_string)
+        .chain(current_env.clear_commands(&shell))
+        .chain([current_env.set(
+            &shell,
+            &env,
+            walker.vals.iter().map(|(k, v)| (*k, v)),
+            walker.aliases.keys().copied(),
+            &walker.exit,
+        )])
+        .chain(walker.set_commands(&shell, &current_env))
+        .chain(walker.alias_commands(&shell, namespace.as_deref()))
+        .chain(
+            walker
+                .enter
+                .iter()
+                .filter_map(|hook| hook.shell_command())
+                .map(str::to_string),
+        );
 
     for command in commands {
         println!("{command}");
@@ -69,34 +189,118 @@ fn set(args: Set) -> eyre::Result<()> {
     if env.is_empty() && variables.is_empty() {
         eprintln!("Environment cleared");
     } else {
-        eprintln!("Environment set: {env} {variables}");
+        let from = if files.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " (from {})",
+                files.iter().map(|file| file.display()).join(", ")
+            )
+        };
+        eprintln!("Environment set: {env} {variables}{from}");
     }
 
     Ok(())
 }
 
-fn complete(args: Complete) -> eyre::Result<()> {
-    let config = cli::load_config_file(args.config.file.as_deref())?;
-
-    if args.env.len() > 1 {
-        // Already complete
-        return Ok(());
+/// Parse the name of an environment into the path of keys to each of the
+/// environments it composes, which are joined by `+`.
+fn parse_env(env: &str) -> eyre::Result<Vec<Vec<Key>>> {
+    let names = env.split('+').collect::<Vec<_>>();
+    if names.len() > 1 && names.contains(&"") {
+        return Err(eyre!("Invalid environment '{env}'; it has an empty part"));
     }
+    names
+        .into_iter()
+        .map(|name| {
+            name.split('.')
+                .map(|k| Key::try_from(k.to_string()))
+                .collect()
+        })
+        .collect()
+}
+
+fn envs_name(keys: &[Key]) -> String {
+    keys.iter().join(".")
+}
+
+fn complete(args: Complete) -> eyre::Result<()> {
+    let (config, _) = cli::load_config(&args.config.files()?)?;
 
-    let env = args.env.first().map(Cow::Borrowed).unwrap_or_default();
-    let env = env.as_str();
+    // Each argument may compose several environments, so we complete the last
+    // part of the last one.
+    let env = args.env.last().map(String::as_str).unwrap_or_default();
+    let (prefix, env) = env.split_at(env.rfind('+').map_or(0, |idx| idx + 1));
 
     // We could do this more efficiently by filtering as we construct deep_keys, but it shouldn't
     // matter in practice.
-    for key in deep_keys(&config).filter(|key| key.starts_with(env)) {
-        println!("{key}");
+    let envs =
+        deep_keys(&config).filter(|key| key.name.starts_with(env) && key.has_tags(&args.tag));
+    for key in envs {
+        let name = format!("{prefix}{}", key.name);
+        match args.shell {
+            Some(shell) => println!("{}", shell.completion(&name, key.description())),
+            None => println!("{name}"),
+        }
     }
 
     Ok(())
 }
 
 fn setup(args: Setup) -> eyre::Result<()> {
-    println!("{}", args.shell.setup());
+    let Setup {
+        shell,
+        name,
+        namespace,
+        file,
+    } = args;
+    check_alias_name(&name).wrap_err_with(|| eyre!("Invalid function name '{name}'"))?;
+    let mut args = Vec::new();
+    if let Some(namespace) = namespace {
+        check_namespace(&namespace)?;
+        args.extend(["--namespace".to_string(), namespace]);
+    }
+    if let Some(file) = file {
+        // The function may be run from anywhere, so a relative path wouldn't
+        // stay correct.
+        let file = std::path::absolute(&file)
+            .wrap_err_with(|| eyre!("Failed to find {}", file.display()))?;
+        args.extend(["--file".to_string(), file.display().to_string()]);
+    }
+    println!("{}", shell.setup(&name, &args));
+    Ok(())
+}
+
+fn cache(args: Cache) -> eyre::Result<()> {
+    match args.command {
+        CacheCommand::Clear => {
+            cache::clear()?;
+            eprintln!("Cache cleared");
+        }
+    }
+    Ok(())
+}
+
+fn encrypt(args: Encrypt) -> eyre::Result<()> {
+    let Encrypt {
+        config,
+        env,
+        var,
+        recipients,
+    } = args;
+    let recipients = crypt::recipients(&recipients.recipients, &recipients.recipients_files)?;
+    let file = config.require()?;
+    crypt::encrypt_var(&file, &env, &var, &recipients)?;
+    eprintln!("Encrypted {var} in {}", file.display());
+    Ok(())
+}
+
+fn rekey(args: Rekey) -> eyre::Result<()> {
+    let Rekey { config, recipients } = args;
+    let recipients = crypt::recipients(&recipients.recipients, &recipients.recipients_files)?;
+    let file = config.require()?;
+    let count = crypt::rekey(&file, &recipients)?;
+    eprintln!("Re-encrypted {count} values in {}", file.display());
     Ok(())
 }
 
@@ -108,9 +312,12 @@ fn main() -> eyre::Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Get => get(),
+        Commands::Get(args) => get(args),
         Commands::Set(args) => set(args),
         Commands::Setup(args) => setup(args),
+        Commands::Cache(args) => cache(args),
+        Commands::Encrypt(args) => encrypt(args),
+        Commands::Rekey(args) => rekey(args),
         Commands::Complete(args) => complete(args),
     }
 }
@@ -0,0 +1,120 @@
+//! Fetching values from external secret managers, through a small protocol
+//! that anyone can implement.
+//!
+//! A value like `{ provider = "vault", ref = "secret/prod#token" }` is fetched
+//! by running `envswitch-provider-vault` from `PATH`. It is sent a request as
+//! JSON on stdin, holding every ref it is needed for when setting an
+//! environment:
+//!
+//! ```json
+//! { "version": 1, "refs": ["secret/prod#token", "secret/prod#password"] }
+//! ```
+//!
+//! It should print a response as JSON to stdout, with a value for each ref, and
+//! exit successfully:
+//!
+//! ```json
+//! { "values": { "secret/prod#token": "abc", "secret/prod#password": "hunter2" } }
+//! ```
+//!
+//! If some refs can't be fetched, it may instead give a message for each under
+//! `errors`, such as `{ "errors": { "secret/prod#token": "permission denied" } }`.
+//! Anything it prints to stderr is shown to the user.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use eyre::{Context, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::source;
+
+/// The version of the protocol, which is sent with each request.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    version: u32,
+    refs: &'a [&'a str],
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(default)]
+    values: HashMap<String, String>,
+    #[serde(default)]
+    errors: HashMap<String, String>,
+}
+
+/// Check that `name` can only refer to an executable on `PATH`.
+pub fn check_name(name: &str) -> eyre::Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "invalid provider '{name}'; use only letters, digits, '-', and '_'"
+        ))
+    }
+}
+
+/// Fetch the value of each of `refs` from `provider`, in a single call.
+pub fn fetch<'a>(
+    provider: &str,
+    refs: &[&str],
+    vars: impl Iterator<Item = (&'a str, Option<String>)>,
+) -> eyre::Result<HashMap<String, String>> {
+    let program = format!("envswitch-provider-{provider}");
+    let mut command = Command::new(&program);
+    source::set_env(&mut command, vars);
+    let mut run = || -> eyre::Result<Response> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => eyre!("{program} was not found on your PATH"),
+                _ => eyre!(err),
+            })?;
+
+        let request = serde_json::to_vec(&Request {
+            version: VERSION,
+            refs,
+        })?;
+        // Take stdin, so that it's closed once written.
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(&request)?;
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(eyre!("{program} failed with {}", output.status));
+        }
+        serde_json::from_slice(&output.stdout)
+            .wrap_err_with(|| eyre!("{program} gave an invalid response"))
+    };
+    let Response { mut values, errors } =
+        run().wrap_err_with(|| eyre!("Failed to run provider '{provider}'"))?;
+
+    for reference in refs {
+        if !values.contains_key(*reference) {
+            return Err(match errors.get(*reference) {
+                Some(error) => {
+                    eyre!("Provider '{provider}' failed to fetch '{reference}': {error}")
+                }
+                None => eyre!("Provider '{provider}' gave no value for '{reference}'"),
+            });
+        }
+    }
+    values.retain(|reference, _| refs.contains(&reference.as_str()));
+    Ok(values)
+}
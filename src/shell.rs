@@ -23,23 +23,120 @@ impl fmt::Display for Shell {
 }
 
 impl Shell {
-    pub fn setup(&self) -> String {
+    /// The script that defines the function `name`, which passes `args` to
+    /// each command before the user's own arguments.
+    pub fn setup(&self, name: &str, args: &[String]) -> String {
         let bin = env::args().next().unwrap();
         // NOTE: These scripts should use BIN as the binary name for envswitch,
-        // which we will sub-in at runtime as a very simple templating
-        // mechanism.
+        // NAME as the name of the function, and ARGS for the arguments, which
+        // we will sub-in at runtime as a very simple templating mechanism.
         let script = match self {
             Shell::Bash => include_str!("shell/bash_setup.sh"),
             Shell::Fish => include_str!("shell/fish_setup.fish"),
             Shell::Zsh => include_str!("shell/zsh_setup.zsh"),
         };
-        script.replace("BIN", &bin)
+        // ARGS is followed directly by the user's arguments, so it brings its
+        // own separating space.
+        let args = args
+            .iter()
+            .map(|arg| format!("{} ", self.quote(arg)))
+            .collect::<String>();
+        let placeholders = [("BIN", bin.as_str()), ("NAME", name), ("ARGS", &args)];
+
+        // We substitute in a single pass so that we don't substitute again
+        // into the values, such as a file path that contains "NAME".
+        let mut output = String::with_capacity(script.len());
+        let mut rest = script;
+        while !rest.is_empty() {
+            match placeholders
+                .iter()
+                .find(|(placeholder, _)| rest.starts_with(placeholder))
+            {
+                Some((placeholder, value)) => {
+                    output.push_str(value);
+                    rest = &rest[placeholder.len()..];
+                }
+                None => {
+                    let ch = rest.chars().next().unwrap();
+                    output.push(ch);
+                    rest = &rest[ch.len_utf8()..];
+                }
+            }
+        }
+        output
     }
 
     pub fn set_var(&self, var: &str, value: &str) -> String {
+        let value = self.quote(value);
         match self {
-            Shell::Bash | Shell::Zsh => format!("export {var}=\"{value}\""),
-            Shell::Fish => format!("set -gx {var} \"{value}\""),
+            Shell::Bash | Shell::Zsh => format!("export {var}={value}"),
+            Shell::Fish => format!("set -gx {var} {value}"),
+        }
+    }
+
+    /// Whether this shell accepts `name` as the name of a variable.
+    pub fn is_valid_var_name(&self, name: &str) -> bool {
+        let mut chars = name.chars();
+        let Some(first) = chars.next() else {
+            return false;
+        };
+        match self {
+            Shell::Bash | Shell::Zsh => {
+                (first.is_ascii_alphabetic() || first == '_')
+                    && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+            }
+            // Fish allows any alphanumeric character, including a leading
+            // digit.
+            Shell::Fish => name.chars().all(|ch| ch.is_alphanumeric() || ch == '_'),
+        }
+    }
+
+    /// Define an alias, first saving any definition it shadows so that
+    /// `clear_alias` can restore it.
+    pub fn set_alias(&self, name: &str, command: &str, namespace: Option<&str>) -> String {
+        let command = self.quote(command);
+        let saved = saved_alias(name, namespace);
+        match self {
+            // `alias` prints a definition that we can evaluate to restore it.
+            Shell::Bash => {
+                format!("{saved}=$(alias {name} 2>/dev/null || true); alias {name}={command}")
+            }
+            Shell::Zsh => {
+                format!("{saved}=$(alias -L {name} 2>/dev/null || true); alias {name}={command}")
+            }
+            // Aliases are functions in fish, which we can copy aside.
+            Shell::Fish => format!(
+                "functions -e {saved}; \
+                 functions -q {name}; and functions -c {name} {saved}; \
+                 alias {name} {command}"
+            ),
+        }
+    }
+
+    /// Remove an alias, restoring any definition it shadowed.
+    pub fn clear_alias(&self, name: &str, namespace: Option<&str>) -> String {
+        let saved = saved_alias(name, namespace);
+        match self {
+            // The user may have removed it already, which is fine.
+            Shell::Bash | Shell::Zsh => {
+                format!("unalias {name} 2>/dev/null; eval \"${{{saved}-}}\"; unset {saved}")
+            }
+            Shell::Fish => format!(
+                "functions -e {name}; \
+                 functions -q {saved}; and functions -c {saved} {name}; \
+                 functions -e {saved}"
+            ),
+        }
+    }
+
+    /// A completion of an environment, with a description for those shells
+    /// that show them.
+    pub fn completion(&self, name: &str, description: Option<&str>) -> String {
+        match (self, description) {
+            // Names can't contain a `:`, so we needn't escape them.
+            (Shell::Zsh, Some(description)) => format!("{name}:{description}"),
+            (Shell::Fish, Some(description)) => format!("{name}\t{description}"),
+            _ => name.to_string(),
         }
     }
 
@@ -49,6 +146,46 @@ impl Shell {
             Shell::Fish => format!("set -e {var}"),
         }
     }
+
+    /// Quote a string so that the shell reads it back verbatim, without
+    /// performing any expansion.
+    fn quote(&self, value: &str) -> String {
+        match self {
+            // Nothing is special inside single quotes in bash and zsh, not even
+            // a backslash, so a single quote has to be written by closing the
+            // quoted string, adding an escaped quote, and re-opening it.
+            Shell::Bash | Shell::Zsh => format!("'{}'", value.replace('\'', r"'\''")),
+            // Fish allows escaping backslashes and single quotes inside single
+            // quotes, and nothing else is special there.
+            Shell::Fish => format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'")),
+        }
+    }
+}
+
+/// The name we save the definition that the alias `name` shadows under, which
+/// must be a valid variable or function name. It includes the namespace, as
+/// each may define the same alias over the other's.
+fn saved_alias(name: &str, namespace: Option<&str>) -> String {
+    // Alias names may contain `-`, so we escape every character that isn't
+    // alphanumeric. As an escape is never followed by `_`, `__` only ever
+    // separates the namespace from the name.
+    fn escape(saved: &mut String, part: &str) {
+        for ch in part.chars() {
+            if ch.is_ascii_alphanumeric() {
+                saved.push(ch);
+            } else {
+                saved.push_str(&format!("_{:02x}", ch as u32));
+            }
+        }
+    }
+
+    let mut saved = "_envswitch_alias_".to_string();
+    if let Some(namespace) = namespace {
+        escape(&mut saved, namespace);
+        saved.push_str("__");
+    }
+    escape(&mut saved, name);
+    saved
 }
 
 #[cfg(test)]
@@ -57,7 +194,8 @@ pub mod test {
 
     use crate::shell::Shell;
 
-    use rstest_reuse::{self, template};
+    use rstest::rstest;
+    use rstest_reuse::{self, apply, template};
 
     #[template]
     #[rstest]
@@ -89,6 +227,7 @@ pub mod test {
                     format!(
                         "set -euo pipefail; \
                          bind 'set show-all-if-ambiguous on'; \
+                         envswitch() {{ {bin} \"$@\"; }}; \
                          source <({bin} setup {self})"
                     )
                 }
@@ -96,10 +235,14 @@ pub mod test {
                     format!(
                         "set -euo pipefail; \
                          unsetopt LIST_AMBIGUOUS; \
+                         envswitch() {{ {bin} \"$@\"; }}; \
                          source <({bin} setup {self})"
                     )
                 }
-                Shell::Fish => format!("{bin} setup fish | source"),
+                Shell::Fish => format!(
+                    "function envswitch; {bin} $argv; end; \
+                     {bin} setup fish | source"
+                ),
             }
         }
 
@@ -113,4 +256,53 @@ pub mod test {
             }
         }
     }
+
+    const QUOTING_CASES: &[&str] = &[
+        "",
+        "plain",
+        "with  spaces",
+        "double \" quote",
+        "single ' quote",
+        "''",
+        "$HOME",
+        "${HOME}",
+        "$(echo injected)",
+        "`echo injected`",
+        "back\\slash",
+        "trailing backslash\\",
+        "\\'",
+        "multi\nline\nvalue\n",
+        "\n",
+        "tab\tseparated",
+        "glob * ? [a-z] ~",
+        "history !! !$",
+        "semi; colon && pipe | amp &",
+        "{\"json\": [1, 2, {\"nested\": \"\\u00e9\"}]}",
+        "unicode: ünïcödé ✓ 🦀",
+        "%s %d",
+    ];
+
+    #[apply(shell_cases)]
+    fn quoting_round_trip(#[case] shell: Shell) {
+        for value in QUOTING_CASES {
+            let script = format!("{}\nprintf '%s' \"$VAR\"", shell.set_var("VAR", value));
+            let output = shell
+                .shell_command()
+                .arg("-c")
+                .arg(&script)
+                .output()
+                .unwrap();
+
+            assert!(
+                output.status.success(),
+                "Script:\n{script}\nStderr: {}",
+                str::from_utf8(&output.stderr).unwrap()
+            );
+            assert_eq!(
+                str::from_utf8(&output.stdout).unwrap(),
+                *value,
+                "Script:\n{script}"
+            );
+        }
+    }
 }
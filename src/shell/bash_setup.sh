@@ -1,10 +1,10 @@
-es() {
+NAME() {
     local env
-    env=$(BIN set -sbash "$@") || return $?
+    env=$(BIN set -sbash ARGS"$@") || return $?
     eval "$env"
 }
 
-_es_completion() {
+_NAME_completion() {
     local cur prev opts
     COMPREPLY=()
     cur="${COMP_WORDS[COMP_CWORD]}"
@@ -17,11 +17,14 @@ _es_completion() {
             mapfile -t COMPREPLY < <(compgen -f -- "${cur}")
             return 0
             ;;
+        -t|--tag)
+            return 0
+            ;;
     esac
 
     # Handle flags
     if [[ ${cur} == -* ]]; then
-        opts="-f --file -l --list"
+        opts="-f --file -l --list -t --tag -e --explain"
         mapfile -t COMPREPLY < <(compgen -W "${opts}" -- "${cur}")
         return 0
     fi
@@ -29,8 +32,8 @@ _es_completion() {
     # Handle dynamic env argument
     local args=("${COMP_WORDS[@]:1}")
     local completions
-    mapfile -t completions < <(BIN complete "${args[@]}" 2>/dev/null)
+    mapfile -t completions < <(BIN complete ARGS"${args[@]}" 2>/dev/null)
     mapfile -t COMPREPLY < <(compgen -W "${completions[*]}" -- "${cur}")
 }
 
-complete -F _es_completion es
+complete -F _NAME_completion NAME
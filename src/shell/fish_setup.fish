@@ -1,14 +1,19 @@
-function es
-    BIN set -sfish $argv | source
+function NAME
+    BIN set -sfish ARGS$argv | source
     return $pipestatus[1]
 end
 
-complete -c es -e
-complete -c es -s f -l file -d "Config file" -r -F
-complete -c es -s l -l list -d "List available environments"
+complete -c NAME -e
+complete -c NAME -s f -l file -d "Config file" -r -F
+complete -c NAME -s l -l list -d "List available environments"
+complete -c NAME -s t -l tag -d "Only list environments with this tag" -x
+complete -c NAME -s e -l explain -d "Show which file each variable is set from"
 
-function __es_complete_positional
-    BIN complete (commandline -opc)[2..] 2>/dev/null
+function __NAME_complete_positional
+    # Pass the token being completed even when it's empty, as it's the one we
+    # complete.
+    set -l current (commandline -ct)
+    BIN complete --shell fish ARGS(commandline -opc)[2..] "$current" 2>/dev/null
 end
 
-complete -c es -f -a '(__es_complete_positional)'
+complete -c NAME -f -a '(__NAME_complete_positional)'
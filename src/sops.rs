@@ -0,0 +1,236 @@
+//! Decrypting config files encrypted with [sops](https://getsops.io), using an
+//! age identity.
+//!
+//! Sops encrypts each value in a document separately, with a data key that is
+//! itself encrypted for each recipient and stored under the `sops` key. A MAC
+//! over all of the values guards against them being changed or reordered.
+
+use aes_gcm::{
+    AesGcm, KeyInit,
+    aead::{Aead, Payload, consts::U32},
+    aes::Aes256,
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use eyre::{Context, eyre};
+use serde::Deserialize;
+use sha2::{Digest, Sha512};
+
+use crate::crypt;
+
+/// The key that sops keeps its metadata under.
+const METADATA_KEY: &str = "sops";
+
+/// What sops starts the MAC with when it only covers encrypted values, so that
+/// it differs from one covering all values.
+const MAC_ONLY_ENCRYPTED_INIT: [u8; 32] = [
+    0x8a, 0x3f, 0xd2, 0xad, 0x54, 0xce, 0x66, 0x52, 0x7b, 0x10, 0x34, 0xf3, 0xd1, 0x47, 0xbe, 0x0b,
+    0x0b, 0x97, 0x5b, 0x3b, 0xf4, 0x4f, 0x72, 0xc6, 0xfd, 0xad, 0xec, 0x81, 0x76, 0xf2, 0x7d, 0x69,
+];
+
+/// Sops uses a 256-bit nonce, rather than the usual 96 bits.
+type Cipher = AesGcm<Aes256, U32>;
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    #[serde(default)]
+    age: Vec<AgeKey>,
+    lastmodified: toml::Value,
+    mac: String,
+    #[serde(default)]
+    mac_only_encrypted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AgeKey {
+    enc: String,
+}
+
+/// Whether `document` has been encrypted with sops. We check for the shape of
+/// sops' metadata, not just its key, as `sops` is also a fine name for an
+/// environment.
+pub fn is_encrypted(document: &toml::Table) -> bool {
+    document
+        .get(METADATA_KEY)
+        .and_then(toml::Value::as_table)
+        .is_some_and(|metadata| {
+            metadata.get("mac").is_some_and(toml::Value::is_str)
+                && (metadata.contains_key("lastmodified") || metadata.contains_key("version"))
+        })
+}
+
+/// Decrypt a document encrypted with sops, using the identity in
+/// `$ENVSWITCH_AGE_IDENTITY`.
+pub fn decrypt(mut document: toml::Table) -> eyre::Result<toml::Table> {
+    let metadata: Metadata = document
+        .remove(METADATA_KEY)
+        .ok_or_else(|| eyre!("Missing sops metadata"))?
+        .try_into()
+        .wrap_err("Invalid sops metadata")?;
+
+    let key = data_key(&metadata)?;
+    let mut hasher = Sha512::new();
+    if metadata.mac_only_encrypted {
+        hasher.update(MAC_ONLY_ENCRYPTED_INIT);
+    }
+    let mut decryptor = Decryptor {
+        cipher: Cipher::new_from_slice(&key).map_err(|_| eyre!("Invalid sops data key"))?,
+        mac_only_encrypted: metadata.mac_only_encrypted,
+        hasher,
+    };
+    for (key, value) in document.iter_mut() {
+        decryptor.decrypt_value(value, &[key])?;
+    }
+
+    let lastmodified = match metadata.lastmodified {
+        toml::Value::String(lastmodified) => lastmodified,
+        other => other.to_string(),
+    };
+    let mac = decryptor
+        .decrypt_str(&metadata.mac, &lastmodified)
+        .wrap_err("Failed to decrypt sops MAC")?;
+    let expected = format!("{:X}", decryptor.hasher.finalize());
+    if mac.value != expected {
+        return Err(eyre!(
+            "The sops MAC does not match; the file may have been tampered with"
+        ));
+    }
+
+    Ok(document)
+}
+
+/// Decrypt the data key with the first age key we can.
+fn data_key(metadata: &Metadata) -> eyre::Result<Vec<u8>> {
+    if metadata.age.is_empty() {
+        return Err(eyre!(
+            "This file has no age recipients; only age is supported for sops files"
+        ));
+    }
+    let identities = crypt::identities()?;
+    metadata
+        .age
+        .iter()
+        .find_map(|key| crypt::decrypt_bytes(&key.enc, &identities).ok())
+        .ok_or_else(|| {
+            eyre!(
+                "None of the age recipients of this file match ${}",
+                crypt::IDENTITY_VAR
+            )
+        })
+}
+
+struct Decryptor {
+    cipher: Cipher,
+    mac_only_encrypted: bool,
+    hasher: Sha512,
+}
+
+/// A decrypted value, and the type sops recorded for it.
+struct Plaintext {
+    value: String,
+    kind: String,
+}
+
+impl Decryptor {
+    fn decrypt_value(&mut self, value: &mut toml::Value, path: &[&str]) -> eyre::Result<()> {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table.iter_mut() {
+                    let path = [path, &[key.as_str()]].concat();
+                    self.decrypt_value(value, &path)?;
+                }
+            }
+            // Array elements share the path of the array.
+            toml::Value::Array(values) => {
+                for value in values {
+                    self.decrypt_value(value, path)?;
+                }
+            }
+            toml::Value::String(encrypted) if encrypted.starts_with("ENC[") => {
+                // Sops authenticates each value along with its path.
+                let aad = format!("{}:", path.join(":"));
+                let Plaintext {
+                    value: plaintext,
+                    kind,
+                } = self
+                    .decrypt_str(encrypted, &aad)
+                    .wrap_err_with(|| eyre!("Failed to decrypt '{}'", path.join(".")))?;
+                *value = typed_value(plaintext, &kind)
+                    .wrap_err_with(|| eyre!("Invalid value for '{}'", path.join(".")))?;
+                self.hash(value);
+            }
+            value if !self.mac_only_encrypted => self.hash(value),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Decrypt a value of the form `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]`.
+    fn decrypt_str(&self, encrypted: &str, aad: &str) -> eyre::Result<Plaintext> {
+        let fields = encrypted
+            .strip_prefix("ENC[AES256_GCM,")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .ok_or_else(|| eyre!("Unsupported sops value; only AES256_GCM is supported"))?;
+        let field = |name: &str| {
+            fields
+                .split(',')
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix(':'))
+                .ok_or_else(|| eyre!("Missing '{name}' in sops value"))
+        };
+        let decode = |name: &str| -> eyre::Result<Vec<u8>> {
+            BASE64_STANDARD
+                .decode(field(name)?)
+                .wrap_err_with(|| eyre!("Invalid '{name}' in sops value"))
+        };
+
+        let iv = decode("iv")?;
+        if iv.len() != 32 {
+            return Err(eyre!("Invalid 'iv' in sops value"));
+        }
+        // The tag goes at the end of the ciphertext.
+        let mut data = decode("data")?;
+        data.extend(decode("tag")?);
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                iv.as_slice().into(),
+                Payload {
+                    msg: &data,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| eyre!("Decryption failed; the file may have been tampered with"))?;
+        Ok(Plaintext {
+            value: String::from_utf8(plaintext).wrap_err("Decrypted value is not valid UTF-8")?,
+            kind: field("type")?.to_string(),
+        })
+    }
+
+    /// Add a value to the MAC, formatted as sops does.
+    fn hash(&mut self, value: &toml::Value) {
+        match value {
+            toml::Value::String(value) => self.hasher.update(value),
+            toml::Value::Integer(value) => self.hasher.update(value.to_string()),
+            toml::Value::Float(value) => self.hasher.update(value.to_string()),
+            toml::Value::Boolean(true) => self.hasher.update("True"),
+            toml::Value::Boolean(false) => self.hasher.update("False"),
+            toml::Value::Datetime(value) => self.hasher.update(value.to_string()),
+            toml::Value::Array(_) | toml::Value::Table(_) => (),
+        }
+    }
+}
+
+fn typed_value(plaintext: String, kind: &str) -> eyre::Result<toml::Value> {
+    let value = match kind {
+        "str" | "bytes" => toml::Value::String(plaintext),
+        "int" => toml::Value::Integer(plaintext.parse()?),
+        "float" => toml::Value::Float(plaintext.parse()?),
+        "bool" => toml::Value::Boolean(match plaintext.as_str() {
+            "True" | "true" => true,
+            "False" | "false" => false,
+            _ => return Err(eyre!("'{plaintext}' is not a boolean")),
+        }),
+        other => return Err(eyre!("Unsupported sops type '{other}'")),
+    };
+    Ok(value)
+}
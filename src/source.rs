@@ -0,0 +1,440 @@
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    time::Duration,
+};
+
+use eyre::{Context, eyre};
+use indexmap::IndexMap;
+use serde::{Deserialize, de};
+
+use crate::{cache, crypt, tty};
+
+/// Change `vars` in the environment of `command`; `None` removes a variable.
+/// We use this to give commands the environment we're switching to, rather
+/// than the one we're leaving.
+pub fn set_env<'a>(
+    command: &mut process::Command,
+    vars: impl Iterator<Item = (&'a str, Option<String>)>,
+) {
+    for (var, value) in vars {
+        match value {
+            Some(value) => command.env(var, value),
+            None => command.env_remove(var),
+        };
+    }
+}
+
+/// Whether `table` is written in the form of a value: it has one of the keys
+/// in `markers`, and only keys in `keys`. Any other table is an environment,
+/// which may well set variables named like these keys.
+pub fn has_form(table: &toml::Table, markers: &[&str], keys: &[&str]) -> bool {
+    let table_keys = table.keys().map(String::as_str).collect::<Vec<_>>();
+    has_form_keys(&table_keys, markers, keys)
+}
+
+/// Like `has_form`, given only the keys of a table.
+pub fn has_form_keys(table_keys: &[&str], markers: &[&str], keys: &[&str]) -> bool {
+    markers.iter().any(|marker| table_keys.contains(marker))
+        && table_keys.iter().all(|key| keys.contains(key))
+}
+
+/// A value read from a file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSource {
+    file: String,
+    /// Whether to remove a trailing newline, as most editors add one.
+    #[serde(default = "default_trim")]
+    trim: bool,
+    /// The directory of the config file, which relative paths are relative to.
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+fn default_trim() -> bool {
+    true
+}
+
+impl FileSource {
+    pub fn matches(table: &toml::Table) -> bool {
+        has_form(table, &["file"], &["file", "trim"])
+    }
+
+    pub fn with_dir(self, dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            ..self
+        }
+    }
+
+    pub fn read(&self) -> eyre::Result<String> {
+        let path = self.path()?;
+        let mut contents = fs::read_to_string(&path)
+            .wrap_err_with(|| eyre!("Failed to read file {}", path.display()))?;
+        if self.trim {
+            trim_newline(&mut contents);
+        }
+        Ok(contents)
+    }
+
+    fn path(&self) -> eyre::Result<PathBuf> {
+        expand_path(&self.file, &self.dir)
+    }
+}
+
+/// A value produced by running a command.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSource {
+    command: CommandLine,
+    /// Whether to remove a trailing newline, as most commands print one.
+    #[serde(default = "default_trim")]
+    trim: bool,
+    /// How long to cache the command's output for; it is not cached if unset.
+    #[serde(default, deserialize_with = "deserialize_ttl")]
+    ttl: Option<Duration>,
+    /// The config file this command is from, which its cache entry is keyed
+    /// on.
+    #[serde(skip)]
+    config: PathBuf,
+}
+
+fn deserialize_ttl<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let ttl = String::deserialize(deserializer)?;
+    humantime::parse_duration(&ttl)
+        .map(Some)
+        .map_err(|err| de::Error::custom(format!("invalid ttl '{ttl}': {err}")))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged, expecting = "a string or an array of strings")]
+enum CommandLine {
+    /// A command to be run by `sh`.
+    Shell(String),
+    /// A program and its arguments, to be run directly.
+    Args(Vec<String>),
+}
+
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandLine::Shell(command) => command.fmt(f),
+            CommandLine::Args(args) => args.join(" ").fmt(f),
+        }
+    }
+}
+
+impl CommandSource {
+    pub fn matches(table: &toml::Table) -> bool {
+        has_form(table, &["command"], &["command", "trim", "ttl"])
+    }
+
+    pub fn with_config(self, config: &Path) -> Self {
+        Self {
+            config: config.to_path_buf(),
+            ..self
+        }
+    }
+
+    /// Run the command, with `vars` changed in its environment, as in
+    /// `set_env`.
+    pub fn read<'a>(
+        &self,
+        vars: impl Iterator<Item = (&'a str, Option<String>)>,
+    ) -> eyre::Result<String> {
+        let mut output = self.run_cached(vars)?;
+        if self.trim {
+            trim_newline(&mut output);
+        }
+        Ok(output)
+    }
+
+    /// Run the command, unless we have its output cached.
+    fn run_cached<'a>(
+        &self,
+        vars: impl Iterator<Item = (&'a str, Option<String>)>,
+    ) -> eyre::Result<String> {
+        let Some(ttl) = self.ttl else {
+            return self.run(vars);
+        };
+        // We key on the debug representation, as it distinguishes e.g.
+        // `"a b"` from `["a", "b"]`.
+        let key = cache::key(&self.config, &format!("{:?}", self.command));
+        if let Some(output) = cache::get(&key) {
+            return Ok(output);
+        }
+
+        let output = self.run(vars)?;
+        // Failing to cache shouldn't stop us from setting the environment.
+        if let Err(err) = cache::put(&key, &output, ttl) {
+            eprintln!("Warning: {err:#}");
+        }
+        Ok(output)
+    }
+
+    /// Run the command, returning what it prints to stdout. Its stderr is
+    /// passed through, so the user can see any errors or prompts.
+    fn run<'a>(
+        &self,
+        vars: impl Iterator<Item = (&'a str, Option<String>)>,
+    ) -> eyre::Result<String> {
+        let mut command = match &self.command {
+            CommandLine::Shell(command) => {
+                let mut cmd = process::Command::new("sh");
+                cmd.arg("-c").arg(command);
+                cmd
+            }
+            CommandLine::Args(args) => {
+                let (program, args) = args
+                    .split_first()
+                    .ok_or_else(|| eyre!("Command cannot be empty"))?;
+                let mut cmd = process::Command::new(program);
+                cmd.args(args);
+                cmd
+            }
+        };
+        set_env(&mut command, vars);
+        let output = command
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .wrap_err_with(|| eyre!("Failed to run command `{}`", self.command))?;
+
+        if !output.status.success() {
+            return Err(eyre!(
+                "Command `{}` failed with {}",
+                self.command,
+                output.status
+            ));
+        }
+        String::from_utf8(output.stdout)
+            .wrap_err_with(|| eyre!("Command `{}` printed invalid UTF-8", self.command))
+    }
+}
+
+/// A value typed in by the user each time it is set, for things that should
+/// never be stored, such as one-time codes.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PromptSource {
+    prompt: String,
+    /// Whether to hide what the user types.
+    #[serde(default)]
+    secret: bool,
+}
+
+impl PromptSource {
+    pub fn matches(table: &toml::Table) -> bool {
+        has_form(table, &["prompt"], &["prompt", "secret"])
+    }
+
+    pub fn read(&self) -> eyre::Result<String> {
+        tty::prompt(&self.prompt, self.secret)
+            .wrap_err_with(|| eyre!("Failed to prompt for '{}'", self.prompt))
+    }
+}
+
+/// A value encrypted with age, decrypted with the identity in
+/// `$ENVSWITCH_AGE_IDENTITY`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgeSource {
+    age: String,
+}
+
+impl AgeSource {
+    pub fn matches(table: &toml::Table) -> bool {
+        has_form(table, &[crypt::AGE_KEY], &[crypt::AGE_KEY])
+    }
+
+    /// Like `matches`, given only the keys of a table, such as one being
+    /// edited.
+    pub fn matches_keys(table_keys: &[&str]) -> bool {
+        has_form_keys(table_keys, &[crypt::AGE_KEY], &[crypt::AGE_KEY])
+    }
+
+    pub fn read(&self) -> eyre::Result<String> {
+        crypt::decrypt(&self.age, &crypt::identities()?).wrap_err("Failed to decrypt value")
+    }
+}
+
+/// A value fetched by an external provider; see the `provider` module.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderSource {
+    pub provider: String,
+    #[serde(rename = "ref")]
+    pub reference: String,
+}
+
+impl ProviderSource {
+    pub fn matches(table: &toml::Table) -> bool {
+        has_form(table, &["provider"], &["provider", "ref"])
+    }
+}
+
+/// Several values taken from the structured output of a single command or
+/// file.
+#[derive(Debug)]
+pub struct ExtractSource {
+    input: Input,
+    format: Format,
+    /// The variables to set, and the path to the value for each.
+    pub fields: IndexMap<String, String>,
+}
+
+#[derive(Debug)]
+enum Input {
+    Command(CommandSource),
+    File(FileSource),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Json,
+    Toml,
+}
+
+/// How an `ExtractSource` is written in the config.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawExtract {
+    command: Option<CommandLine>,
+    file: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_ttl")]
+    ttl: Option<Duration>,
+    json: Option<IndexMap<String, String>>,
+    toml: Option<IndexMap<String, String>>,
+}
+
+impl ExtractSource {
+    pub fn matches(table: &toml::Table) -> bool {
+        has_form(
+            table,
+            &["json", "toml"],
+            &["command", "file", "ttl", "json", "toml"],
+        )
+    }
+
+    /// Parse the table form of this, from the config file `config`.
+    pub fn parse(table: toml::Table, config: &Path) -> eyre::Result<Self> {
+        let raw: RawExtract = toml::Value::Table(table).try_into()?;
+
+        let input = match (raw.command, raw.file) {
+            (Some(command), None) => Input::Command(CommandSource {
+                command,
+                trim: false,
+                ttl: raw.ttl,
+                config: config.to_path_buf(),
+            }),
+            (None, Some(_)) if raw.ttl.is_some() => {
+                return Err(eyre!("`ttl` may only be set along with `command`"));
+            }
+            (None, Some(file)) => Input::File(FileSource {
+                file,
+                trim: false,
+                dir: config.parent().unwrap_or(Path::new("")).to_path_buf(),
+            }),
+            _ => return Err(eyre!("Exactly one of `command` or `file` must be set")),
+        };
+        let (format, fields) = match (raw.json, raw.toml) {
+            (Some(fields), None) => (Format::Json, fields),
+            (None, Some(fields)) => (Format::Toml, fields),
+            _ => return Err(eyre!("Only one of `json` or `toml` may be set")),
+        };
+
+        Ok(Self {
+            input,
+            format,
+            fields,
+        })
+    }
+
+    /// Run the command (with `vars` changed in its environment) or read the
+    /// file, and parse its contents. As this may run a command, it should only
+    /// be done once.
+    pub fn load<'a>(
+        &self,
+        vars: impl Iterator<Item = (&'a str, Option<String>)>,
+    ) -> eyre::Result<serde_json::Value> {
+        let (contents, description) = match &self.input {
+            Input::Command(source) => (
+                source.run_cached(vars)?,
+                format!("output of `{}`", source.command),
+            ),
+            Input::File(source) => (source.read()?, source.path()?.display().to_string()),
+        };
+        let document = match self.format {
+            Format::Json => serde_json::from_str(&contents).map_err(eyre::Error::from),
+            Format::Toml => toml::from_str(&contents).map_err(eyre::Error::from),
+        };
+        document.wrap_err_with(|| eyre!("Failed to parse {description}"))
+    }
+}
+
+/// Find the value at `path` in `document`, using a subset of jq's syntax, such
+/// as `.Credentials.AccessKeyId`, `.items[0].name`, or `."dotted.key"`.
+///
+/// Strings are returned as-is; anything else is returned as JSON.
+pub fn extract(document: &serde_json::Value, path: &str) -> eyre::Result<String> {
+    let not_found = || eyre!("Nothing found at '{path}'");
+
+    let mut value = document;
+    let mut rest = path.strip_prefix('.').unwrap_or(path);
+    while !rest.is_empty() {
+        let after = if let Some(after) = rest.strip_prefix('[') {
+            let (index, after) = after
+                .split_once(']')
+                .ok_or_else(|| eyre!("Unterminated '[' in '{path}'"))?;
+            let index = index
+                .parse::<usize>()
+                .wrap_err_with(|| eyre!("Invalid index '{index}' in '{path}'"))?;
+            value = value.get(index).ok_or_else(not_found)?;
+            after
+        } else if let Some(after) = rest.strip_prefix('"') {
+            let (key, after) = after
+                .split_once('"')
+                .ok_or_else(|| eyre!("Unterminated '\"' in '{path}'"))?;
+            value = value.get(key).ok_or_else(not_found)?;
+            after
+        } else {
+            let (key, after) = rest.split_at(rest.find(['.', '[']).unwrap_or(rest.len()));
+            value = value.get(key).ok_or_else(not_found)?;
+            after
+        };
+        rest = after.strip_prefix('.').unwrap_or(after);
+    }
+
+    match value {
+        serde_json::Value::String(value) => Ok(value.clone()),
+        serde_json::Value::Null => Err(eyre!("The value at '{path}' is null")),
+        value => Ok(value.to_string()),
+    }
+}
+
+/// Resolve `path` relative to `dir`, expanding a leading `~` to the user's home
+/// directory.
+pub fn expand_path(path: &str, dir: &Path) -> eyre::Result<PathBuf> {
+    let home = || env::home_dir().ok_or_else(|| eyre!("Could not determine home directory"));
+    let path = match path.strip_prefix('~') {
+        Some("") => home()?,
+        Some(rest) if rest.starts_with('/') => home()?.join(&rest[1..]),
+        _ => dir.join(path),
+    };
+    Ok(path)
+}
+
+/// Remove a single trailing newline, if there is one.
+pub fn trim_newline(s: &mut String) {
+    if s.ends_with('\n') {
+        s.pop();
+        if s.ends_with('\r') {
+            s.pop();
+        }
+    }
+}
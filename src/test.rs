@@ -8,6 +8,7 @@ use crate::shell::Shell;
 use crate::shell::test::shell_cases;
 use crate::test::helpers::assert_completions;
 use crate::test::helpers::run_command;
+use crate::test::helpers::run_interactive;
 
 mod helpers;
 
@@ -131,6 +132,1567 @@ fn base(#[case] shell: Shell) {
     );
 }
 
+#[apply(shell_cases)]
+fn restore_previous_value(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &["export URL=original", "es prod.abc", "es"].join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("Environment set: prod.abc ");
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "URL" => "original",
+            "ENVSWITCH_ENV" => ":GLOBAL",
+            "GLOBAL" => "some global variable",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn restore_across_switches(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[
+            "export GLOBAL=mine",
+            "es staging.abc",
+            "es prod",
+            "es staging",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "GLOBAL" => "some global variable",
+            "ENVSWITCH_ENV" => "staging:GLOBAL=mine,URL",
+            "URL" => "staging.com",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn restore_escaped_value(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &["export URL='a,b:c=d%e'", "es prod"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "URL" => "prod.com",
+            "ENVSWITCH_ENV" => "prod:GLOBAL,URL=a%2Cb%3Ac%3Dd%25e",
+            "GLOBAL" => "override for production",
+        }
+    );
+
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &["export URL='a,b:c=d%e'", "es prod", "es"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "URL" => "a,b:c=d%e",
+            "ENVSWITCH_ENV" => ":GLOBAL",
+            "GLOBAL" => "some global variable",
+        }
+    );
+}
+
+static SPLICE_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [node]
+        MYPATH = { prepend = "./node_modules/.bin" }
+
+        [node.tools]
+        MYPATH = { append = "/opt/tools/bin:/opt/tools/sbin" }
+
+        [python]
+        PYPATH = "/usr/lib/python"
+
+        [python.venv]
+        PYPATH = { prepend = "./venv/lib", separator = ";" }
+    }
+});
+
+#[apply(shell_cases)]
+fn splice(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &SPLICE_CONFIG,
+        &["export MYPATH=/usr/bin:/bin", "es node.tools"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "MYPATH" => "./node_modules/.bin:/usr/bin:/bin:/opt/tools/bin:/opt/tools/sbin",
+            "ENVSWITCH_ENV" =>
+                "node.tools:MYPATH+%3A+<./node_modules/.bin+>/opt/tools/bin+>/opt/tools/sbin",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn splice_clear_keeps_user_changes(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &SPLICE_CONFIG,
+        &[
+            "export MYPATH=/usr/bin:/bin",
+            "es node.tools",
+            "export MYPATH=\"/user/bin:$MYPATH\"",
+            "es node",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "MYPATH" => "./node_modules/.bin:/user/bin:/usr/bin:/bin",
+            "ENVSWITCH_ENV" => "node:MYPATH+%3A+<./node_modules/.bin",
+        }
+    );
+
+    let r = run_command(
+        shell,
+        &SPLICE_CONFIG,
+        &[
+            "export MYPATH=/usr/bin:/bin",
+            "es node.tools",
+            "export MYPATH=\"/user/bin:$MYPATH\"",
+            "es",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "MYPATH" => "/user/bin:/usr/bin:/bin",
+            "ENVSWITCH_ENV" => ":",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn splice_clear_keeps_order(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [a]
+        MYPATH = { append = "/x" }
+    };
+    let r = run_command(
+        shell,
+        &config,
+        &["export MYPATH=/x:/y", "es a", "es"].join("\n"),
+    );
+    r.assert_success();
+
+    // We remove the `/x` we appended, not the user's own.
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "MYPATH" => "/x:/y",
+            "ENVSWITCH_ENV" => ":",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn splice_unset_variable(#[case] shell: Shell) {
+    let r = run_command(shell, &SPLICE_CONFIG, "es node");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "MYPATH" => "./node_modules/.bin",
+            "ENVSWITCH_ENV" => "node:MYPATH+%3A+<./node_modules/.bin",
+        }
+    );
+
+    let r = run_command(shell, &SPLICE_CONFIG, &["es node", "es"].join("\n"));
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => ":",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn splice_over_set(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &SPLICE_CONFIG,
+        &["export PYPATH=/mine", "es python.venv"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "PYPATH" => "./venv/lib;/usr/lib/python",
+            "ENVSWITCH_ENV" => "python.venv:PYPATH=/mine",
+        }
+    );
+}
+
+static UNSET_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [prod]
+        KEY = "prod_key"
+        URL = "prod.com"
+
+        [prod.readonly]
+        KEY = { unset = true }
+
+        [prod.readonly.admin]
+        KEY = "admin_key"
+    }
+});
+
+#[apply(shell_cases)]
+fn unset(#[case] shell: Shell) {
+    let r = run_command(shell, &UNSET_CONFIG, "es prod.readonly");
+    r.assert_success();
+
+    r.assert_stderr_includes("Environment set: prod.readonly URL (from ");
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.readonly:URL",
+            "URL" => "prod.com",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn unset_existing_variable(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &UNSET_CONFIG,
+        &["export KEY=outside", "es prod", "es prod.readonly"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.readonly:KEY=outside,URL",
+            "URL" => "prod.com",
+        }
+    );
+
+    let r = run_command(
+        shell,
+        &UNSET_CONFIG,
+        &["export KEY=outside", "es prod.readonly", "es"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => ":",
+            "KEY" => "outside",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn unset_then_set(#[case] shell: Shell) {
+    let r = run_command(shell, &UNSET_CONFIG, "es prod.readonly.admin");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.readonly.admin:KEY,URL",
+            "KEY" => "admin_key",
+            "URL" => "prod.com",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn typed_values(#[case] shell: Shell) {
+    let config = toml::toml! {
+        PORT  = 8080
+        DEBUG = true
+        RATIO = 1.0
+        START = 1979-05-27T07:32:00Z
+        DIRS  = ["/a", "/b"]
+        MIXED = ["a", 1, false]
+
+        [csv]
+        LIST   = { value = ["a", "b", "c"], separator = "," }
+        MYPATH = { prepend = ["/x", "/y"] }
+    };
+    let r = run_command(shell, &config, "es csv");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "csv:PORT,DEBUG,RATIO,START,DIRS,MIXED,LIST,MYPATH+%3A+</x+</y",
+            "PORT" => "8080",
+            "DEBUG" => "true",
+            "RATIO" => "1.0",
+            "START" => "1979-05-27T07:32:00Z",
+            "DIRS" => "/a:/b",
+            "MIXED" => "a:1:false",
+            "LIST" => "a,b,c",
+            "MYPATH" => "/x:/y",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn unsupported_value(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod]
+        NESTED = [[1, 2], [3]]
+    };
+    let r = run_command(shell, &config, "es prod");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Invalid value for 'prod.NESTED'");
+    r.assert_stderr_includes("expected a string, number, boolean, or datetime, found array");
+}
+
+static INTERPOLATION_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        BASE_URL = "https://example.com"
+        API      = "${BASE_URL}/api/v2"
+
+        [prod]
+        BASE_URL = "https://prod.com"
+
+        [outside]
+        DIR    = "${ORIGIN}/work"
+        MYPATH = "${MYPATH}:/extra"
+
+        [fallback]
+        URL   = "${MISSING:-http://localhost:${PORT:-3000}}"
+        PRICE = "$$5 and $literal"
+
+        [cycle]
+        A = "${B}"
+        B = "${C}"
+        C = "${A}"
+
+        [undefined]
+        X = "${NOPE}"
+    }
+});
+
+#[apply(shell_cases)]
+fn interpolation(#[case] shell: Shell) {
+    let r = run_command(shell, &INTERPOLATION_CONFIG, "es prod");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod:BASE_URL,API",
+            "BASE_URL" => "https://prod.com",
+            "API" => "https://prod.com/api/v2",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn interpolation_from_environment(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &INTERPOLATION_CONFIG,
+        &[
+            "export ORIGIN=/home/me",
+            "export MYPATH=/bin",
+            "es outside",
+            "es outside",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "outside:BASE_URL,API,DIR,MYPATH=/bin",
+            "BASE_URL" => "https://example.com",
+            "API" => "https://example.com/api/v2",
+            "ORIGIN" => "/home/me",
+            "DIR" => "/home/me/work",
+            "MYPATH" => "/bin:/extra",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn interpolation_defaults(#[case] shell: Shell) {
+    let r = run_command(shell, &INTERPOLATION_CONFIG, "es fallback");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "fallback:BASE_URL,API,URL,PRICE",
+            "BASE_URL" => "https://example.com",
+            "API" => "https://example.com/api/v2",
+            "URL" => "http://localhost:3000",
+            "PRICE" => "$5 and $literal",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn interpolation_cycle(#[case] shell: Shell) {
+    let r = run_command(shell, &INTERPOLATION_CONFIG, "es cycle");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Variable references form a cycle: A -> B -> C -> A");
+}
+
+#[apply(shell_cases)]
+fn interpolation_undefined(#[case] shell: Shell) {
+    let r = run_command(shell, &INTERPOLATION_CONFIG, "es undefined");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("'NOPE' is not defined");
+}
+
+static FILE_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [files]
+        TOKEN      = { file = "token.txt" }
+        RAW        = { file = "token.txt", trim = false }
+        HOME_TOKEN = { file = "~/token.txt" }
+
+        [missing]
+        TOKEN = { file = "nope.txt" }
+
+        [missing.overridden]
+        TOKEN = "overridden"
+    }
+});
+
+#[apply(shell_cases)]
+fn file_value(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &FILE_CONFIG,
+        &[
+            "printf 'secret\\n' > token.txt",
+            "export HOME=$PWD",
+            "mkdir sub",
+            "cd sub",
+            "es -f ../envswitch.toml files",
+            "printf '[%s]\\n' \"$RAW\"",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("[secret\n]");
+
+    let env = r.env_diff();
+    assert_eq!(env["ENVSWITCH_ENV"], "files:TOKEN,RAW,HOME_TOKEN");
+    assert_eq!(env["TOKEN"], "secret");
+    assert_eq!(env["HOME_TOKEN"], "secret");
+}
+
+#[apply(shell_cases)]
+fn file_value_missing(#[case] shell: Shell) {
+    let r = run_command(shell, &FILE_CONFIG, "es missing");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Failed to set environment 'missing'");
+    r.assert_stderr_includes("Failed to resolve 'TOKEN'");
+    r.assert_stderr_includes("nope.txt");
+}
+
+#[apply(shell_cases)]
+fn file_value_only_read_when_used(#[case] shell: Shell) {
+    let r = run_command(shell, &FILE_CONFIG, "es missing.overridden");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "missing.overridden:TOKEN",
+            "TOKEN" => "overridden",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn environment_with_value_keys(#[case] shell: Shell) {
+    // These tables set variables that share names with the keys of values,
+    // but have other variables too, so they're environments.
+    let config = toml::toml! {
+        [prod]
+        file = "notes"
+        URL  = "prod.com"
+
+        [dev]
+        command = "echo hi"
+        URL     = "dev.com"
+    };
+    let r = run_command(shell, &config, "es -l\nes prod");
+    r.assert_success();
+
+    r.assert_stderr_includes("  prod\n");
+    r.assert_stderr_includes("  dev\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod:file,URL",
+            "file" => "notes",
+            "URL" => "prod.com",
+        }
+    );
+}
+
+static COMMAND_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [commands]
+        TOKEN = { command = "echo secret" }
+        ARGS  = { command = ["printf", "%s", "two words"] }
+
+        [commands.failing]
+        BAD = { command = "echo oops >&2; exit 3" }
+
+        [other]
+        SIDE_EFFECT = { command = "touch ran" }
+    }
+});
+
+#[apply(shell_cases)]
+fn command_value(#[case] shell: Shell) {
+    let r = run_command(shell, &COMMAND_CONFIG, "es commands");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "commands:TOKEN,ARGS",
+            "TOKEN" => "secret",
+            "ARGS" => "two words",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn command_value_failure(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &COMMAND_CONFIG,
+        &["es commands", "es commands.failing || echo failed"].join("\n"),
+    );
+    r.assert_success();
+    r.assert_stdout_includes("failed\n");
+
+    // The first command succeeded; the second should have changed nothing.
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "commands:TOKEN,ARGS",
+            "TOKEN" => "secret",
+            "ARGS" => "two words",
+        }
+    );
+    r.assert_stderr_includes("oops");
+    r.assert_stderr_includes("Failed to resolve 'BAD'");
+    r.assert_stderr_includes("Command `echo oops >&2; exit 3` failed with exit status: 3");
+}
+
+#[apply(shell_cases)]
+fn command_value_only_run_when_used(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &COMMAND_CONFIG,
+        &["es -l", "es commands", "test ! -e ran"].join("\n"),
+    );
+    r.assert_success();
+}
+
+#[apply(shell_cases)]
+fn command_value_sees_new_environment(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod]
+        PROFILE = "prod"
+
+        [staging]
+        WHO = { command = "echo \"profile=${PROFILE:-none}\"" }
+        creds = { command = "printf '{\"who\": \"%s\"}' \"${PROFILE:-none}\"", json = { WHO_JSON = ".who" } }
+    };
+    let r = run_command(shell, &config, "es prod\nes staging");
+    r.assert_success();
+
+    // Commands don't see the variables of the environment we're leaving.
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "staging:WHO,WHO_JSON",
+            "WHO" => "profile=none",
+            "WHO_JSON" => "none",
+        }
+    );
+}
+
+static EXTRACT_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [aws]
+        creds = {
+            command = "echo run >> runs.log; cat creds.json",
+            json = {
+                AWS_ACCESS_KEY_ID = ".Credentials.AccessKeyId",
+                AWS_SECRET_ACCESS_KEY = ".Credentials.SecretAccessKey",
+                EXPIRATION = ".Credentials.Expiration",
+                ROLES = ".Roles",
+                FIRST_ROLE = ".Roles[0].name",
+            }
+        }
+
+        [aws.override]
+        EXPIRATION = "never"
+
+        [db]
+        db = { file = "db.toml", toml = { DB_NAME = "name", DB_PASSWORD = ".auth.password" } }
+
+        [missing]
+        creds = { command = "cat creds.json", json = { NOPE = ".Credentials.Nope" } }
+    }
+});
+
+const CREDS_JSON: &str = r#"{
+    "Credentials": {
+        "AccessKeyId": "AKIA123",
+        "SecretAccessKey": "s3cr3t",
+        "Expiration": 1700000000
+    },
+    "Roles": [{"name": "admin"}, {"name": "dev"}]
+}"#;
+
+#[apply(shell_cases)]
+fn extract_from_command(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &EXTRACT_CONFIG,
+        &[
+            &format!("echo '{CREDS_JSON}' > creds.json"),
+            "es aws.override",
+            "awk 'END { print \"runs: \" NR }' runs.log",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("runs: 1\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "aws.override:AWS_ACCESS_KEY_ID,AWS_SECRET_ACCESS_KEY,EXPIRATION,ROLES,FIRST_ROLE",
+            "AWS_ACCESS_KEY_ID" => "AKIA123",
+            "AWS_SECRET_ACCESS_KEY" => "s3cr3t",
+            "EXPIRATION" => "never",
+            "ROLES" => r#"[{"name":"admin"},{"name":"dev"}]"#,
+            "FIRST_ROLE" => "admin",
+        }
+    );
+
+    let r = run_command(
+        shell,
+        &EXTRACT_CONFIG,
+        &[&format!("echo '{CREDS_JSON}' > creds.json"), "es aws", "es"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(r.env_diff(), indexmap! { "ENVSWITCH_ENV" => ":" });
+}
+
+#[apply(shell_cases)]
+fn extract_from_file(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &EXTRACT_CONFIG,
+        &[
+            "printf 'name = \"app\"\\n[auth]\\npassword = \"hunter2\"\\n' > db.toml",
+            "es db",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "db:DB_NAME,DB_PASSWORD",
+            "DB_NAME" => "app",
+            "DB_PASSWORD" => "hunter2",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn extract_missing_field(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &EXTRACT_CONFIG,
+        &[
+            &format!("echo '{CREDS_JSON}' > creds.json"),
+            "es missing || echo failed",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("failed\n");
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Failed to resolve 'NOPE'");
+    r.assert_stderr_includes("Nothing found at '.Credentials.Nope'");
+}
+
+static CACHE_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [a]
+        VALUE = { command = "echo run >> runs.log; echo a", ttl = "1h" }
+
+        [b]
+        VALUE = { command = "echo run >> runs.log; echo b", ttl = "1h" }
+
+        [short]
+        VALUE = { command = "echo run >> runs.log; echo short", ttl = "1s" }
+
+        [creds]
+        creds = { command = "echo run >> runs.log; echo '{\"key\": \"k\"}'", ttl = "1h", json = { KEY = ".key" } }
+    }
+});
+
+#[apply(shell_cases)]
+fn cached_command(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CACHE_CONFIG,
+        &[
+            "es a",
+            "es b",
+            "es a",
+            "awk 'END { print \"runs: \" NR }' runs.log",
+            "find cache/envswitch -type f -perm 600",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    // Each command is only run once, but changing the command gives it a new
+    // entry.
+    r.assert_stdout_includes("runs: 2\n");
+    r.assert_stdout_includes("cache/envswitch/");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "a:VALUE",
+            "VALUE" => "a",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn cached_command_expires(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CACHE_CONFIG,
+        &[
+            "es short",
+            "sleep 2",
+            "es short",
+            "awk 'END { print \"runs: \" NR }' runs.log",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("runs: 2\n");
+}
+
+#[apply(shell_cases)]
+fn cached_extract(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CACHE_CONFIG,
+        &[
+            "es creds",
+            "es creds",
+            "awk 'END { print \"runs: \" NR }' runs.log",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("runs: 1\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "creds:KEY",
+            "KEY" => "k",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn cache_clear(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CACHE_CONFIG,
+        &[
+            "es a",
+            "envswitch cache clear",
+            "es a",
+            "awk 'END { print \"runs: \" NR }' runs.log",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("runs: 2\n");
+    r.assert_stderr_includes("Cache cleared");
+}
+
+static PROMPT_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [mfa]
+        CODE = { prompt = "MFA code", secret = true }
+
+        [ticket]
+        TICKET = { prompt = "Ticket" }
+    }
+});
+
+#[apply(shell_cases)]
+fn prompt_secret(#[case] shell: Shell) {
+    run_interactive(shell, &PROMPT_CONFIG, |p| {
+        p.send_line("es mfa")?;
+        p.exp_string("MFA code: ")?;
+        p.send_line("123456")?;
+        let typed = p.exp_string("Environment set: mfa CODE")?;
+        assert!(!typed.contains("123456"), "secret was echoed: {typed}");
+
+        p.send_line("echo \"code=${CODE}.\"")?;
+        p.exp_string("code=123456.")?;
+        Ok(())
+    });
+}
+
+#[apply(shell_cases)]
+fn prompt_visible(#[case] shell: Shell) {
+    run_interactive(shell, &PROMPT_CONFIG, |p| {
+        p.send_line("es ticket")?;
+        p.exp_string("Ticket: ")?;
+        p.send_line("ABC-123")?;
+        let typed = p.exp_string("Environment set: ticket TICKET")?;
+        assert!(typed.contains("ABC-123"), "input was not echoed: {typed}");
+
+        p.send_line("echo \"ticket=${TICKET}.\"")?;
+        p.exp_string("ticket=ABC-123.")?;
+        Ok(())
+    });
+}
+
+#[apply(shell_cases)]
+fn prompt_cancelled(#[case] shell: Shell) {
+    run_interactive(shell, &PROMPT_CONFIG, |p| {
+        // The shell runs with `set -e`, so we need to catch the failure.
+        p.send_line("es mfa || echo aborted")?;
+        p.exp_string("MFA code: ")?;
+        p.send("12")?;
+        p.send_control('c')?;
+        p.exp_string("Cancelled")?;
+        p.exp_string("\naborted")?;
+
+        p.send_line("echo \"[${ENVSWITCH_ENV:-none}]\"")?;
+        p.exp_string("[none]")?;
+        Ok(())
+    });
+}
+
+static AGE_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [prod]
+        TOKEN = "hunter2"
+        USER = "admin"
+
+        [prod.nested]
+        KEY = "nested secret"
+    }
+});
+
+// Test keys, generated for these tests only.
+const AGE_KEY_1: &str =
+    "AGE-SECRET-KEY-1N7VEQ3HY880YK2KLPCZ4R8LDAWY8HLE72D36VMQ4TRRUM4QPPSRQHPPNMH";
+const AGE_KEY_2: &str =
+    "AGE-SECRET-KEY-17SYS3M8AZQAED75XZ3D9GLMREMREQT8N5SHNN7MU80FYTNV953SQLXSHG6";
+const AGE_RECIPIENT_1: &str = "age1n8uvctk8rr05u07jsxwq8pz56ems5dpjvynt2ej5dv04ry6l532sqd36gh";
+const AGE_RECIPIENT_2: &str = "age1egqyr6lxgmh7vaz7nvuzuezqwgfrtcd49urmqfdfq3gj8f88eqkspdnyya";
+
+#[apply(shell_cases)]
+fn age_encrypt(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &AGE_CONFIG,
+        &[
+            &format!("echo {AGE_KEY_1} > key.txt"),
+            "ENVSWITCH_AGE_IDENTITY=key.txt envswitch encrypt prod TOKEN",
+            "ENVSWITCH_AGE_IDENTITY=key.txt envswitch encrypt prod.nested KEY",
+            "echo \"plaintext: $(grep -c -e hunter2 -e 'nested secret' envswitch.toml)\"",
+            "ENVSWITCH_AGE_IDENTITY=key.txt es prod.nested",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("plaintext: 0\n");
+    r.assert_stderr_includes("Encrypted TOKEN in ");
+    r.assert_stderr_includes("/envswitch.toml\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.nested:TOKEN,USER,KEY",
+            "TOKEN" => "hunter2",
+            "USER" => "admin",
+            "KEY" => "nested secret",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn age_encrypt_to_recipient(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &AGE_CONFIG,
+        &[
+            &format!("echo {AGE_RECIPIENT_2} > recipients.txt"),
+            &format!("echo {AGE_KEY_1} > key1.txt"),
+            &format!("echo {AGE_KEY_2} > key2.txt"),
+            "envswitch encrypt -R recipients.txt prod TOKEN",
+            "ENVSWITCH_AGE_IDENTITY=key1.txt es prod || echo failed",
+            "ENVSWITCH_AGE_IDENTITY=key2.txt es prod",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("failed\n");
+    r.assert_stderr_includes("Failed to decrypt value");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod:TOKEN,USER",
+            "TOKEN" => "hunter2",
+            "USER" => "admin",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn age_rekey(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &AGE_CONFIG,
+        &[
+            &format!("echo {AGE_KEY_1} > key1.txt"),
+            &format!("echo {AGE_KEY_2} > key2.txt"),
+            &format!("envswitch encrypt -r {AGE_RECIPIENT_1} prod TOKEN"),
+            &format!("envswitch encrypt -r {AGE_RECIPIENT_1} prod.nested KEY"),
+            &format!("ENVSWITCH_AGE_IDENTITY=key1.txt envswitch rekey -r {AGE_RECIPIENT_2}"),
+            "ENVSWITCH_AGE_IDENTITY=key1.txt es prod || echo failed",
+            "ENVSWITCH_AGE_IDENTITY=key2.txt es prod.nested",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("Re-encrypted 2 values in ");
+    r.assert_stderr_includes("/envswitch.toml\n");
+    r.assert_stdout_includes("failed\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.nested:TOKEN,USER,KEY",
+            "TOKEN" => "hunter2",
+            "USER" => "admin",
+            "KEY" => "nested secret",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn age_rekey_variable_named_age(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &toml::toml! {
+            [prod]
+            TOKEN = "hunter2"
+
+            [person]
+            name = "Ann"
+            age = "30"
+        },
+        &[
+            &format!("echo {AGE_KEY_1} > key1.txt"),
+            &format!("echo {AGE_KEY_2} > key2.txt"),
+            &format!("envswitch encrypt -r {AGE_RECIPIENT_1} prod TOKEN"),
+            &format!("ENVSWITCH_AGE_IDENTITY=key1.txt envswitch rekey -r {AGE_RECIPIENT_2}"),
+            "ENVSWITCH_AGE_IDENTITY=key2.txt es person",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("Re-encrypted 1 values in ");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "person:name,age",
+            "name" => "Ann",
+            "age" => "30",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn age_missing_identity(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &AGE_CONFIG,
+        &[
+            &format!("envswitch encrypt -r {AGE_RECIPIENT_1} prod TOKEN"),
+            &format!("envswitch encrypt -r {AGE_RECIPIENT_1} prod TOKEN || echo failed"),
+            "es prod || echo failed",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("'TOKEN' is already encrypted");
+    r.assert_stderr_includes("ENVSWITCH_AGE_IDENTITY is not set");
+    assert!(r.env_diff().is_empty());
+}
+
+/// Files in sops' format encrypted for `AGE_RECIPIENT_1`, from the same config.
+/// sops has no TOML store, so these were written following its format rather
+/// than by sops itself; `example.yaml` below checks them against the real thing.
+const SOPS_FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/test/sops");
+
+/// The key for `sops/example.yaml`, which sops itself wrote from its example
+/// file with `sops --encrypt --age <recipient>`, for the recipient
+/// `age1se5ghfycr4n8kcwc3qwf234ymvmr2lex2a99wh8gpfx97glwt9hqch4569`. Both are
+/// taken from the test references of [rops](https://github.com/gibbz00/rops).
+const SOPS_EXAMPLE_KEY: &str =
+    "AGE-SECRET-KEY-1EQUCGFZH8UZKSZ0Z5N5T234YRNDT4U9H7QNYXWRRNJYDDVXE6FWSCPGNJ7";
+
+#[apply(shell_cases)]
+fn sops_file(#[case] shell: Shell, #[values("yaml", "json", "toml")] format: &str) {
+    let r = run_command(
+        shell,
+        &toml::Table::new(),
+        &[
+            format!("echo {AGE_KEY_1} > key.txt"),
+            format!("cp {SOPS_FIXTURES}/envswitch.{format} prod.{format}"),
+            format!("ENVSWITCH_AGE_IDENTITY=key.txt es -f prod.{format} prod.eu"),
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.eu:GLOBAL,DB_PASSWORD,PORT,DEBUG,HOSTS,REGION",
+            "GLOBAL" => "global",
+            "DB_PASSWORD" => "hunter2",
+            "PORT" => "5432",
+            "DEBUG" => "false",
+            "HOSTS" => "a.example.com:b.example.com",
+            "REGION" => "eu-west-1",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn sops_file_from_sops(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &toml::Table::new(),
+        &[
+            format!("echo {SOPS_EXAMPLE_KEY} > key.txt"),
+            format!("cp {SOPS_FIXTURES}/example.yaml example.yaml"),
+            "ENVSWITCH_AGE_IDENTITY=key.txt es -f example.yaml".to_string(),
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => ":hello,example_key,example_array,example_number,example_booleans",
+            "hello" => "Welcome to SOPS! Edit this file as you please!",
+            "example_key" => "example_value",
+            "example_array" => "example_value1:example_value2",
+            "example_number" => "1234.56789",
+            "example_booleans" => "true:false",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn sops_wrong_identity(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &toml::Table::new(),
+        &[
+            &format!("echo {AGE_KEY_2} > key.txt"),
+            &format!("cp {SOPS_FIXTURES}/envswitch.yaml prod.yaml"),
+            "ENVSWITCH_AGE_IDENTITY=key.txt es -f prod.yaml prod || echo failed",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("failed\n");
+    r.assert_stderr_includes(
+        "None of the age recipients of this file match $ENVSWITCH_AGE_IDENTITY",
+    );
+    assert!(r.env_diff().is_empty());
+}
+
+#[apply(shell_cases)]
+fn sops_tampered(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &toml::Table::new(),
+        &[
+            format!("echo {AGE_KEY_1} > key.txt"),
+            // Swap the two hosts, which are each still validly encrypted.
+            format!(
+                "awk 'NR == 7 {{ host = $0; next }} NR == 8 {{ print; print host; next }} 1' \
+                 {SOPS_FIXTURES}/envswitch.yaml > prod.yaml"
+            ),
+            "ENVSWITCH_AGE_IDENTITY=key.txt es -f prod.yaml prod || echo failed".to_string(),
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("failed\n");
+    r.assert_stderr_includes("The sops MAC does not match");
+    assert!(r.env_diff().is_empty());
+}
+
+#[apply(shell_cases)]
+fn sops_environment(#[case] shell: Shell) {
+    // A plain config may have an environment named `sops`.
+    let config = toml::toml! {
+        [sops]
+        SOPS_AGE_KEY_FILE = "/x"
+
+        [prod]
+        URL = "prod.com"
+    };
+    let r = run_command(shell, &config, "es -l\nes sops");
+    r.assert_success();
+
+    r.assert_stderr_includes("  sops\n  prod\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "sops:SOPS_AGE_KEY_FILE",
+            "SOPS_AGE_KEY_FILE" => "/x",
+        }
+    );
+}
+
+static PROVIDER_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [prod]
+        TOKEN = { provider = "stub", ref = "op://work/api/token" }
+        DB_PASSWORD = { provider = "stub", ref = "op://work/db/password" }
+
+        [prod.override]
+        TOKEN = "plain"
+
+        [prod.missing]
+        OTHER = { provider = "stub", ref = "op://work/missing" }
+
+        [unknown]
+        OTHER = { provider = "nope", ref = "anything" }
+    }
+});
+
+/// A provider that logs each request, and knows a fixed set of values.
+const STUB_PROVIDER: &str = r#"printf '%s\n' \
+    '#!/bin/sh' \
+    'cat >> requests.log; echo >> requests.log' \
+    'echo "{\"values\": {\"op://work/api/token\": \"tok\", \"op://work/db/password\": \"pw\"}, \"errors\": {\"op://work/missing\": \"not found\"}}"' \
+    > envswitch-provider-stub
+chmod +x envswitch-provider-stub"#;
+
+#[apply(shell_cases)]
+fn provider(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &PROVIDER_CONFIG,
+        &[
+            STUB_PROVIDER,
+            "PATH=\"$PWD:$PATH\" es prod",
+            "cat requests.log",
+            "awk 'END { print \"calls: \" NR }' requests.log",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    // Both refs are fetched in a single call.
+    r.assert_stdout_includes(
+        "{\"version\":1,\"refs\":[\"op://work/api/token\",\"op://work/db/password\"]}\n",
+    );
+    r.assert_stdout_includes("calls: 1\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod:TOKEN,DB_PASSWORD",
+            "TOKEN" => "tok",
+            "DB_PASSWORD" => "pw",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn provider_only_fetches_used_refs(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &PROVIDER_CONFIG,
+        &[
+            STUB_PROVIDER,
+            "PATH=\"$PWD:$PATH\" es prod.override",
+            "cat requests.log",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("{\"version\":1,\"refs\":[\"op://work/db/password\"]}\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.override:TOKEN,DB_PASSWORD",
+            "TOKEN" => "plain",
+            "DB_PASSWORD" => "pw",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn provider_errors(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &PROVIDER_CONFIG,
+        &[
+            STUB_PROVIDER,
+            "PATH=\"$PWD:$PATH\" es prod.missing || echo failed",
+            "es unknown || echo failed",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("failed\nfailed\n");
+    r.assert_stderr_includes("Provider 'stub' failed to fetch 'op://work/missing': not found");
+    r.assert_stderr_includes("envswitch-provider-nope was not found on your PATH");
+    assert!(r.env_diff().is_empty());
+}
+
+static GENERATE_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [aws]
+        generate = "aws-profiles"
+        AWS_PAGER = ""
+
+        [aws.staging]
+        AWS_PROFILE = "hand-written"
+
+        [kube]
+        generate = "kube-contexts"
+    }
+});
+
+const AWS_CONFIG: &str = "printf '%s\\n' \
+    '[default]' 'region = us-east-1' \
+    '[profile prod]' 'region = eu-west-1' 'output = json' \
+    '[sso-session work]' 'sso_region = us-east-2' \
+    '[profile staging]' \
+    '[profile dev]' \
+    > aws.config";
+
+const KUBE_CONFIG: &str = "printf '%s\\n' \
+    'contexts:' \
+    '- name: kind-local' '  context: {cluster: kind}' \
+    '- name: prod-eks' '  context: {cluster: eks}' \
+    > kube.yaml";
+
+#[apply(shell_cases)]
+fn generated_list(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &GENERATE_CONFIG,
+        &[
+            AWS_CONFIG,
+            KUBE_CONFIG,
+            "AWS_CONFIG_FILE=aws.config KUBECONFIG=kube.yaml es -l",
+            // Listing doesn't write the files that select kube contexts.
+            "[ -e state ] || echo 'no state'",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("no state\n");
+    r.assert_stderr_includes(
+        "  aws\n  aws.staging\n  aws.default\n  aws.prod\n  aws.dev\n  \
+         kube\n  kube.kind-local\n  kube.prod-eks\n",
+    );
+}
+
+#[apply(shell_cases)]
+fn generated_aws_profile(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &GENERATE_CONFIG,
+        &[AWS_CONFIG, "AWS_CONFIG_FILE=aws.config es aws.prod"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "aws.prod:AWS_PAGER,AWS_PROFILE,AWS_REGION",
+            "AWS_PAGER" => "",
+            "AWS_PROFILE" => "prod",
+            "AWS_REGION" => "eu-west-1",
+        }
+    );
+
+    // Tables written by hand take precedence.
+    let r = run_command(
+        shell,
+        &GENERATE_CONFIG,
+        &[AWS_CONFIG, "AWS_CONFIG_FILE=aws.config es aws.staging"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "aws.staging:AWS_PAGER,AWS_PROFILE",
+            "AWS_PAGER" => "",
+            "AWS_PROFILE" => "hand-written",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn generated_kube_context(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &GENERATE_CONFIG,
+        &[
+            KUBE_CONFIG,
+            "KUBECONFIG=kube.yaml es kube.prod-eks",
+            // The file that selects the context isn't part of the cache.
+            "envswitch cache clear",
+            "echo \"$KUBECONFIG\" | cut -d: -f1 | xargs cat >&2",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    let env = r.env_diff();
+    // We set `KUBECONFIG` for `es` itself, so it is restored on clear.
+    assert_eq!(
+        env["ENVSWITCH_ENV"],
+        "kube.prod-eks:KUBECONFIG=kube.yaml,KUBE_CONTEXT"
+    );
+    assert_eq!(env["KUBE_CONTEXT"], "prod-eks");
+    // The context is selected by a file before the user's own.
+    let (selector, rest) = env["KUBECONFIG"].split_once(':').unwrap();
+    assert!(selector.contains("/state/envswitch/kube-contexts/"));
+    assert_eq!(rest, "kube.yaml");
+    r.assert_stderr_includes("current-context: prod-eks\n");
+}
+
+#[apply(shell_cases)]
+fn generated_kube_context_switch(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &GENERATE_CONFIG,
+        &[
+            KUBE_CONFIG,
+            "printf '%s\\n' 'contexts:' '- name: other' > other.yaml",
+            "export KUBECONFIG=kube.yaml:other.yaml",
+            "es kube.kind-local",
+            // We still see every context once `KUBECONFIG` has changed.
+            "es -l",
+            "es kube.other",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("  kube.kind-local\n  kube.prod-eks\n  kube.other\n");
+    let env = r.env_diff();
+    assert_eq!(
+        env["ENVSWITCH_ENV"],
+        "kube.other:KUBECONFIG=kube.yaml%3Aother.yaml,KUBE_CONTEXT"
+    );
+    assert!(env["KUBECONFIG"].ends_with(".yaml:kube.yaml:other.yaml"));
+}
+
+#[apply(shell_cases)]
+fn generated_not_interpolated(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &GENERATE_CONFIG,
+        &[
+            "printf '%s\\n' '[profile cost$$center]' '[profile x${Y}]' > aws.config",
+            "AWS_CONFIG_FILE=aws.config es 'aws.cost$$center'",
+            "echo \"$AWS_PROFILE\"",
+            "AWS_CONFIG_FILE=aws.config es 'aws.x${Y}'",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("cost$$center\n");
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "aws.x${Y}:AWS_PAGER,AWS_PROFILE",
+            "AWS_PAGER" => "",
+            "AWS_PROFILE" => "x${Y}",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn generated_completion(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [aws]
+        generate = "aws-profiles"
+    };
+    let r = run_command(
+        shell,
+        &config,
+        &[
+            "printf '[profile prod]\\n[profile preview]\\n' > aws.config",
+            "AWS_CONFIG_FILE=aws.config envswitch complete aws.pr",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("aws.prod\naws.preview\n");
+}
+
+#[apply(shell_cases)]
+fn unknown_generator(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [gcp]
+        generate = "gcp-projects"
+    };
+    let r = run_command(shell, &config, "es -l || echo failed");
+    r.assert_success();
+
+    r.assert_stdout_includes("failed\n");
+    r.assert_stderr_includes("Invalid value for 'gcp.generate'");
+    r.assert_stderr_includes("unknown generator 'gcp-projects'");
+}
+
+#[apply(shell_cases)]
+fn special_characters(#[case] shell: Shell) {
+    let config = toml::toml! {
+        QUOTES    = "it's a \"test\""
+        EXPANSION = "$HOME $${USER} `whoami` $(whoami)"
+        BACKSLASH = "C:\\path\\"
+    };
+    let r = run_command(shell, &config, "es");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => ":QUOTES,EXPANSION,BACKSLASH",
+            "QUOTES" => "it's a \"test\"",
+            "EXPANSION" => "$HOME ${USER} `whoami` $(whoami)",
+            "BACKSLASH" => "C:\\path\\",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn invalid_variable_name(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod.abc]
+        "$(touch${IFS}pwned)" = "value"
+    };
+    let r = run_command(shell, &config, "es prod");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Invalid variable '$(touch${IFS}pwned)' in [prod.abc]");
+}
+
+#[apply(shell_cases)]
+fn invalid_global_variable_name(#[case] shell: Shell) {
+    let config = toml::toml! {
+        "FOO-BAR" = "value"
+    };
+    let r = run_command(shell, &config, "es -l");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes(
+        "Invalid variable 'FOO-BAR': not a valid variable name in bash, fish, zsh",
+    );
+}
+
 #[apply(shell_cases)]
 fn missing_file(#[case] shell: Shell) {
     let r = run_command(shell, &CONFIG, "es -f foo.toml");
@@ -139,6 +1701,813 @@ fn missing_file(#[case] shell: Shell) {
     assert!(r.env_diff().is_empty());
 }
 
+#[apply(shell_cases)]
+fn discover_config_in_parent(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[
+            "mkdir -p sub/dir",
+            "cd sub/dir",
+            "es prod",
+            "envswitch get --verbose",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    let env = r.env_diff();
+    assert_eq!(env["ENVSWITCH_ENV"], "prod:GLOBAL,URL");
+    assert_eq!(env["URL"], "prod.com");
+    r.assert_stderr_includes("Environment set: prod GLOBAL URL (from ");
+    r.assert_stdout_includes("prod\nConfig file: ");
+    r.assert_stdout_includes("/envswitch.toml\n");
+}
+
+#[apply(shell_cases)]
+fn discover_config_stops_at_git(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[
+            "mkdir -p repo/.git repo/sub",
+            "cd repo/sub",
+            "ENVSWITCH_STOP_AT_GIT=1 envswitch get --verbose || echo no env",
+            "envswitch get --verbose || echo no env",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("Config file: none found\nno env\nConfig file: ");
+    r.assert_stdout_includes("/envswitch.toml\nno env\n");
+}
+
+const USER_CONFIG: &str = "mkdir -p config/envswitch && printf '%s\\n' \
+    'TOKEN = \"personal\"' \
+    '[prod.abc]' 'USER_KEY = \"mine\"' \
+    '[dev]' 'URL = \"localhost\"' \
+    > config/envswitch/config.toml";
+
+const LOCAL_CONFIG: &str = "printf '%s\\n' \
+    '[prod]' 'URL = \"prod.local\"' \
+    '[prod.abc]' 'KEY = \"local_secret\"' \
+    > envswitch.local.toml";
+
+#[apply(shell_cases)]
+fn layered_configs(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[USER_CONFIG, LOCAL_CONFIG, "es prod.abc"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.abc:TOKEN,GLOBAL,URL,USER_KEY,KEY",
+            "TOKEN" => "personal",
+            "GLOBAL" => "override for production",
+            "URL" => "prod.local",
+            "USER_KEY" => "mine",
+            "KEY" => "local_secret",
+        }
+    );
+    r.assert_stderr_includes("/config/envswitch/config.toml, ");
+    r.assert_stderr_includes("/envswitch.toml, ");
+    r.assert_stderr_includes("/envswitch.local.toml)");
+}
+
+#[apply(shell_cases)]
+fn layered_configs_list(#[case] shell: Shell) {
+    let r = run_command(shell, &CONFIG, &[USER_CONFIG, "es -l"].join("\n"));
+    r.assert_success();
+
+    r.assert_stderr_includes("  prod\n  prod.abc\n  dev\n  staging\n");
+}
+
+#[apply(shell_cases)]
+fn layered_configs_explicit_file(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[USER_CONFIG, LOCAL_CONFIG, "es -f envswitch.toml prod.abc"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.abc:GLOBAL,URL,KEY",
+            "GLOBAL" => "override for production",
+            "URL" => "prod.com",
+            "KEY" => "prod_secret_ABC",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn explain(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[USER_CONFIG, LOCAL_CONFIG, "es --explain prod.abc"].join("\n"),
+    );
+    r.assert_success();
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Variables for prod.abc:\n  TOKEN     ");
+    r.assert_stderr_includes("/config/envswitch/config.toml\n  GLOBAL    ");
+    r.assert_stderr_includes("/envswitch.toml\n  URL       ");
+    r.assert_stderr_includes("/envswitch.local.toml\n  USER_KEY  ");
+    r.assert_stderr_includes("/config/envswitch/config.toml\n  KEY       ");
+}
+
+static INCLUDE_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        include = ["envs/*.toml"]
+        GLOBAL = "main"
+
+        [prod]
+        include = "envs/prod/prod.toml"
+        URL = "prod.com"
+    }
+});
+
+const INCLUDED_FILES: &str = "mkdir -p envs/prod \
+    && printf '%s\\n' 'GLOBAL = \"aws\"' '[aws]' 'AWS_PROFILE = \"dev\"' > envs/aws.toml \
+    && printf '%s\\n' '[db]' 'DB_URL = \"postgres://localhost\"' > envs/db.toml \
+    && printf '%s\\n' 'include = \"common.toml\"' 'URL = \"ignored\"' '[eu]' 'REGION = \"eu-west-1\"' \
+        > envs/prod/prod.toml \
+    && printf '%s\\n' 'TEAM = \"platform\"' > envs/prod/common.toml";
+
+#[apply(shell_cases)]
+fn include_list(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &INCLUDE_CONFIG,
+        &[INCLUDED_FILES, "es -l"].join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("  aws\n  db\n  prod\n  prod.eu\n");
+}
+
+#[apply(shell_cases)]
+fn include_glob(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &INCLUDE_CONFIG,
+        &[INCLUDED_FILES, "es aws"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "aws:GLOBAL,AWS_PROFILE",
+            // The including file takes precedence.
+            "GLOBAL" => "main",
+            "AWS_PROFILE" => "dev",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn include_in_table(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &INCLUDE_CONFIG,
+        &[INCLUDED_FILES, "es prod.eu", "es --explain prod.eu"].join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.eu:GLOBAL,TEAM,URL,REGION",
+            "GLOBAL" => "main",
+            "TEAM" => "platform",
+            "URL" => "prod.com",
+            "REGION" => "eu-west-1",
+        }
+    );
+    r.assert_stderr_includes("/envs/prod/common.toml\n  URL     ");
+    r.assert_stderr_includes("/envs/prod/prod.toml\n");
+}
+
+#[apply(shell_cases)]
+fn include_cycle(#[case] shell: Shell) {
+    let config = toml::toml! {
+        include = "a.toml"
+    };
+    let r = run_command(
+        shell,
+        &config,
+        &[
+            "printf '%s\\n' 'include = \"b.toml\"' > a.toml",
+            "printf '%s\\n' 'include = \"a.toml\"' > b.toml",
+            "es -l",
+        ]
+        .join("\n"),
+    );
+    assert_ne!(r.status(), 0);
+
+    r.assert_stderr_includes("Include cycle: ");
+    r.assert_stderr_includes("/envswitch.toml -> ");
+    r.assert_stderr_includes("/a.toml -> ");
+    r.assert_stderr_includes("/b.toml -> ");
+}
+
+#[apply(shell_cases)]
+fn include_missing(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod]
+        include = "missing.toml"
+    };
+    let r = run_command(shell, &config, "es -l");
+    assert_ne!(r.status(), 0);
+
+    r.assert_stderr_includes("Failed to include ");
+    r.assert_stderr_includes("/missing.toml");
+}
+
+static EXTENDS_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [_common.abc]
+        KEY = "common_key"
+        SHARED = "common"
+        LEVEL = "mixin"
+
+        [_observability]
+        OTEL = "on"
+        LEVEL = "observability"
+
+        [prod]
+        URL = "prod.com"
+        LEVEL = "parent"
+
+        [prod.abc]
+        extends = ["_common.abc", "_observability"]
+        SHARED = "own"
+
+        [staging.abc]
+        extends = "_common.abc"
+    }
+});
+
+#[apply(shell_cases)]
+fn extends(#[case] shell: Shell) {
+    let r = run_command(shell, &EXTENDS_CONFIG, "es prod.abc");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.abc:URL,LEVEL,KEY,SHARED,OTEL",
+            "URL" => "prod.com",
+            // Mixins come after parents, in order.
+            "LEVEL" => "observability",
+            "KEY" => "common_key",
+            // The table itself comes last.
+            "SHARED" => "own",
+            "OTEL" => "on",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn extends_again(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [_common]
+        X = "common"
+
+        [prod]
+        extends = "_common"
+        X = "prod"
+
+        [prod.abc]
+        extends = "_common"
+    };
+    let r = run_command(shell, &config, "es prod.abc");
+    r.assert_success();
+
+    // A table's mixins come after its parents, even if a parent has the same
+    // mixin.
+    assert_eq!(r.env_diff()["X"], "common");
+}
+
+#[apply(shell_cases)]
+fn extends_hides_abstract_tables(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &EXTENDS_CONFIG,
+        "es -l\necho start\nenvswitch complete",
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("Available environments:\n  prod\n  prod.abc\n  staging\n");
+    r.assert_stdout_includes("start\nprod\nprod.abc\nstaging\nstaging.abc\n");
+}
+
+#[apply(shell_cases)]
+fn extends_cycle(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [_a]
+        extends = "_b"
+
+        [_b]
+        extends = "_a"
+
+        [prod]
+        extends = "_a"
+    };
+    let r = run_command(shell, &config, "es prod");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Tables extend each other in a cycle: prod -> _a -> _b -> _a");
+}
+
+#[apply(shell_cases)]
+fn extends_missing(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod]
+        extends = "_common"
+    };
+    let r = run_command(shell, &config, "es prod");
+    assert_ne!(r.status(), 0);
+
+    r.assert_stderr_includes("No table '_common' for 'prod' to extend");
+}
+
+static META_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [aws]
+        _meta = { description = "AWS accounts", tags = ["aws", "cloud"] }
+
+        [aws.prod]
+        _meta = { description = "Production", color = "red" }
+        AWS_PROFILE = "prod"
+
+        [aws.internal]
+        _meta = { hidden = true }
+        AWS_PROFILE = "internal"
+
+        [db]
+        _meta = { tags = ["db"] }
+        URL = "postgres://localhost"
+    }
+});
+
+#[apply(shell_cases)]
+fn meta_not_exported(#[case] shell: Shell) {
+    let r = run_command(shell, &META_CONFIG, "es aws.prod");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "aws.prod:AWS_PROFILE",
+            "AWS_PROFILE" => "prod",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn meta_list(#[case] shell: Shell) {
+    let r = run_command(shell, &META_CONFIG, "es -l");
+    r.assert_success();
+
+    r.assert_stderr_includes(
+        "Available environments:\n  aws       AWS accounts\n  aws.prod  Production\n  db\n",
+    );
+}
+
+#[apply(shell_cases)]
+fn meta_list_tag(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &META_CONFIG,
+        "es -l --tag aws\necho done >&2\nes -l -t cloud -t db\necho done >&2",
+    );
+    r.assert_success();
+
+    // Tags apply to nested environments too.
+    r.assert_stderr_includes("  aws       AWS accounts\n  aws.prod  Production\ndone\n");
+    r.assert_stderr_includes("Available environments:\ndone\n");
+}
+
+#[apply(shell_cases)]
+fn meta_completion_descriptions(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &META_CONFIG,
+        "envswitch complete --shell zsh\nenvswitch complete --shell fish a",
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("aws:AWS accounts\naws.prod:Production\ndb\n");
+    r.assert_stdout_includes("aws\tAWS accounts\naws.prod\tProduction\n");
+}
+
+#[apply(shell_cases)]
+fn meta_invalid(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod]
+        _meta = { colour = "red" }
+    };
+    let r = run_command(shell, &config, "es prod");
+    assert_ne!(r.status(), 0);
+
+    r.assert_stderr_includes("Invalid value for 'prod._meta'");
+}
+
+static HOOKS_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [prod]
+        _hooks = { enter = "echo entering prod", exit = "echo leaving prod" }
+        URL = "prod.com"
+
+        [prod.k8s]
+        KEY = "k8s"
+
+        [staging]
+        _hooks.enter = { command = "echo \"entering $URL\"", run = "direct" }
+        _hooks.exit = { command = "echo leaving staging; exit 3", run = "direct" }
+        URL = "staging.com"
+    }
+});
+
+#[apply(shell_cases)]
+fn hooks_inherited(#[case] shell: Shell) {
+    let r = run_command(shell, &HOOKS_CONFIG, "es prod\nes prod.k8s\necho done");
+    r.assert_success();
+
+    // The exit hook is recorded along with what we set.
+    assert_eq!(
+        r.env_diff()["ENVSWITCH_ENV"],
+        "prod.k8s:URL,KEY::secho leaving prod"
+    );
+    // We leave the old environment before entering the new one.
+    r.assert_stdout_includes("entering prod\nleaving prod\nentering prod\ndone\n");
+}
+
+#[apply(shell_cases)]
+fn hooks_from_entered_config(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &HOOKS_CONFIG,
+        &[
+            "mkdir other",
+            "printf '%s\\n' '[prod]' '_hooks.exit = \"echo wrong project\"' > other/envswitch.toml",
+            "es prod",
+            "cd other",
+            "es",
+            "echo done",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    // We run the exit hook of the environment we entered, not that of the
+    // environment with the same name here.
+    r.assert_stdout_includes("entering prod\nleaving prod\ndone\n");
+}
+
+#[apply(shell_cases)]
+fn hooks_direct(#[case] shell: Shell) {
+    let r = run_command(shell, &HOOKS_CONFIG, "es staging\nes\necho done");
+    r.assert_success();
+
+    // Direct hooks see the new environment, and print to stderr.
+    r.assert_stderr_includes("entering staging.com\nEnvironment set: staging URL");
+    r.assert_stderr_includes(
+        "leaving staging\nWarning: Hook `echo leaving staging; exit 3` failed",
+    );
+    r.assert_stderr_includes("Environment cleared");
+    r.assert_stdout_includes("done\n");
+    assert!(r.env_diff().contains_key("ENVSWITCH_ENV"));
+    assert!(!r.env_diff().contains_key("URL"));
+}
+
+#[apply(shell_cases)]
+fn hooks_invalid(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod]
+        _hooks = { enter = { command = "true", run = "later" } }
+    };
+    let r = run_command(shell, &config, "es prod");
+    assert_ne!(r.status(), 0);
+
+    r.assert_stderr_includes("Invalid value for 'prod._hooks'");
+}
+
+static ALIASES_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        [prod]
+        _aliases = { k = "kubectl --context prod", psql-prod = "psql $DATABASE_URL" }
+        URL = "prod.com"
+
+        [prod.eu]
+        _aliases = { k = "kubectl --context prod-eu" }
+    }
+});
+
+/// A command that prints the definition of an alias to stderr, or fails if
+/// there isn't one.
+fn show_alias(shell: Shell, name: &str) -> String {
+    match shell {
+        Shell::Bash | Shell::Zsh => format!("alias {name} >&2"),
+        Shell::Fish => format!("functions {name} >&2"),
+    }
+}
+
+#[apply(shell_cases)]
+fn aliases(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &ALIASES_CONFIG,
+        &[
+            "es prod.eu",
+            &show_alias(shell, "k"),
+            &show_alias(shell, "psql-prod"),
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "prod.eu:URL:k,psql-prod",
+            "URL" => "prod.com",
+        }
+    );
+    // The most specific alias wins.
+    r.assert_stderr_includes("kubectl --context prod-eu");
+    r.assert_stderr_includes("psql $DATABASE_URL");
+}
+
+#[apply(shell_cases)]
+fn aliases_cleared(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &ALIASES_CONFIG,
+        &[
+            "es prod",
+            "es",
+            &format!("{} || echo gone", show_alias(shell, "k")),
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(r.env_diff(), indexmap! { "ENVSWITCH_ENV" => ":" });
+    r.assert_stdout_includes("gone\n");
+}
+
+#[apply(shell_cases)]
+fn aliases_restore_shadowed(#[case] shell: Shell) {
+    let define = match shell {
+        Shell::Bash | Shell::Zsh => "alias psql-prod='echo mine'",
+        Shell::Fish => "alias psql-prod 'echo mine'",
+    };
+    let r = run_command(
+        shell,
+        &ALIASES_CONFIG,
+        &[
+            define,
+            "es prod.eu",
+            "es prod",
+            "es",
+            &show_alias(shell, "psql-prod"),
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    // The user's own alias is back once we leave.
+    r.assert_stderr_includes("echo mine");
+}
+
+#[apply(shell_cases)]
+fn aliases_invalid(#[case] shell: Shell) {
+    let config = toml::toml! {
+        [prod]
+        _aliases = { "k;rm" = "kubectl" }
+    };
+    let r = run_command(shell, &config, "es prod");
+    assert_ne!(r.status(), 0);
+
+    r.assert_stderr_includes("Invalid alias 'k;rm' in 'prod._aliases'");
+}
+
+static COMPOSE_CONFIG: LazyLock<toml::Table> = LazyLock::new(|| {
+    toml::toml! {
+        GLOBAL = "global"
+        TOOLS = { prepend = "root" }
+
+        [db.local]
+        DB_URL = "postgres://localhost"
+        URL = "db"
+
+        [aws.dev]
+        AWS_PROFILE = "dev"
+        URL = "aws"
+    }
+});
+
+#[apply(shell_cases)]
+fn compose(#[case] shell: Shell) {
+    let r = run_command(shell, &COMPOSE_CONFIG, "es db.local+aws.dev\nenvswitch get");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "db.local+aws.dev:GLOBAL,TOOLS+%3A+<root,DB_URL,URL,AWS_PROFILE",
+            "GLOBAL" => "global",
+            // Shared tables, such as the root, only count once.
+            "TOOLS" => "root",
+            "DB_URL" => "postgres://localhost",
+            "URL" => "aws",
+            "AWS_PROFILE" => "dev",
+        }
+    );
+    r.assert_stderr_includes(
+        "Warning: URL is set by both db.local and aws.dev; using the value from aws.dev\n",
+    );
+    r.assert_stdout_includes("db.local+aws.dev\n");
+}
+
+#[apply(shell_cases)]
+fn compose_separate_args(#[case] shell: Shell) {
+    let r = run_command(shell, &COMPOSE_CONFIG, "es aws.dev db.local");
+    r.assert_success();
+
+    let env = r.env_diff();
+    assert_eq!(
+        env["ENVSWITCH_ENV"],
+        "aws.dev+db.local:GLOBAL,TOOLS+%3A+<root,AWS_PROFILE,URL,DB_URL"
+    );
+    assert_eq!(env["URL"], "db");
+}
+
+#[apply(shell_cases)]
+fn compose_switch_away(#[case] shell: Shell) {
+    let r = run_command(shell, &COMPOSE_CONFIG, "es db.local+aws.dev\nes aws.dev");
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "aws.dev:GLOBAL,TOOLS+%3A+<root,AWS_PROFILE,URL",
+            "GLOBAL" => "global",
+            "TOOLS" => "root",
+            "AWS_PROFILE" => "dev",
+            "URL" => "aws",
+        }
+    );
+}
+
+#[apply(shell_cases)]
+fn compose_completion(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &COMPOSE_CONFIG,
+        "envswitch complete db.local+a\nenvswitch complete db.local ''",
+    );
+    r.assert_success();
+
+    r.assert_stdout_includes("db.local+aws\ndb.local+aws.dev\ndb\ndb.local\naws\naws.dev\n");
+}
+
+#[apply(shell_cases)]
+fn compose_empty_part(#[case] shell: Shell) {
+    let r = run_command(shell, &COMPOSE_CONFIG, "es db.local+");
+    assert_ne!(r.status(), 0);
+
+    assert!(r.env_diff().is_empty());
+    r.assert_stderr_includes("Invalid environment 'db.local+'; it has an empty part");
+}
+
+/// A command that defines `esh`, which keeps its state in the `home`
+/// namespace and always uses `file`.
+fn setup_namespace(shell: Shell, file: &str) -> String {
+    let setup = format!("envswitch setup {shell} --name esh --namespace home --file {file}");
+    match shell {
+        Shell::Bash | Shell::Zsh => format!("source <({setup})"),
+        Shell::Fish => format!("{setup} | source"),
+    }
+}
+
+#[apply(shell_cases)]
+fn namespaces(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[
+            "printf '%s\\n' '[laptop]' 'EDITOR = \"vim\"' > home.toml",
+            &setup_namespace(shell, "home.toml"),
+            "es prod",
+            "esh laptop",
+            // Changing one namespace leaves the other alone.
+            "es staging",
+            "envswitch get --all",
+            "envswitch get --namespace home --file home.toml --verbose",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    assert_eq!(
+        r.env_diff(),
+        indexmap! {
+            "ENVSWITCH_ENV" => "staging:GLOBAL,URL",
+            "GLOBAL" => "some global variable",
+            "URL" => "staging.com",
+            "ENVSWITCH_ENV_home" => "laptop:EDITOR",
+            "EDITOR" => "vim",
+        }
+    );
+    r.assert_stdout_includes("staging\nhome: laptop\nlaptop\nConfig file: home.toml\n");
+}
+
+#[apply(shell_cases)]
+fn namespaces_clear(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        &[
+            "printf '%s\\n' '[laptop]' 'EDITOR = \"vim\"' > home.toml",
+            &setup_namespace(shell, "home.toml"),
+            "es prod",
+            "esh laptop",
+            "esh",
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    let env = r.env_diff();
+    assert_eq!(env["ENVSWITCH_ENV"], "prod:GLOBAL,URL");
+    assert_eq!(env["ENVSWITCH_ENV_home"], ":");
+    assert!(!env.contains_key("EDITOR"));
+}
+
+#[apply(shell_cases)]
+fn namespaces_aliases(#[case] shell: Shell) {
+    let define = match shell {
+        Shell::Bash | Shell::Zsh => "alias k='echo mine'",
+        Shell::Fish => "alias k 'echo mine'",
+    };
+    let r = run_command(
+        shell,
+        &ALIASES_CONFIG,
+        &[
+            "printf '%s\\n' '[laptop]' '_aliases = { k = \"echo home\" }' > home.toml",
+            &setup_namespace(shell, "home.toml"),
+            define,
+            "es prod",
+            "esh laptop",
+            // Each namespace restores the alias it shadowed.
+            "esh",
+            &show_alias(shell, "k"),
+            "es",
+            &show_alias(shell, "k"),
+        ]
+        .join("\n"),
+    );
+    r.assert_success();
+
+    r.assert_stderr_includes("kubectl --context prod");
+    r.assert_stderr_includes("echo mine");
+}
+
+#[apply(shell_cases)]
+fn namespaces_invalid(#[case] shell: Shell) {
+    let r = run_command(
+        shell,
+        &CONFIG,
+        "envswitch set -s bash --namespace 'a-b' prod",
+    );
+    assert_ne!(r.status(), 0);
+
+    r.assert_stderr_includes("invalid namespace 'a-b'");
+}
+
+#[apply(shell_cases)]
+fn namespaces_completion(#[case] shell: Shell) {
+    assert_completions(
+        shell,
+        &CONFIG,
+        &format!("{}\nesh stag", setup_namespace(shell, "envswitch.toml")),
+        &["staging", "staging.abc", "staging.def"],
+    );
+}
+
 #[apply(shell_cases)]
 fn list(#[case] shell: Shell) {
     let r = run_command(shell, &CONFIG, "es -l");
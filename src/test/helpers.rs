@@ -6,7 +6,7 @@ use std::{
 
 use eyre::{Context, eyre};
 use indexmap::IndexMap;
-use rexpect::{reader::Options, spawn_with_options};
+use rexpect::{reader::Options, session::PtySession, spawn_with_options};
 
 use crate::shell::Shell;
 
@@ -30,7 +30,7 @@ impl ScriptResult {
         let base_env = parse_env(&self.base_env);
         let stdout: &str = str::from_utf8(&self.output.stdout).unwrap();
 
-        let mut env = parse_env(&stdout);
+        let mut env = parse_env(stdout);
         for (key, val) in base_env {
             if env.get(&key) == Some(&val) {
                 env.swap_remove(&key);
@@ -50,6 +50,15 @@ impl ScriptResult {
         );
     }
 
+    pub fn assert_stdout_includes(&self, s: &str) {
+        let stdout = str::from_utf8(&self.output.stdout).unwrap();
+
+        assert!(
+            stdout.contains(s),
+            "stdout: '{stdout}' does not contain '{s}'"
+        );
+    }
+
     pub fn assert_stderr_includes(&self, s: &str) {
         let stderr = str::from_utf8(&self.output.stderr).unwrap();
 
@@ -78,7 +87,15 @@ fn execute_script(dir: &Path, shell: Shell, name: &str, command: &str) -> eyre::
 
     let script_path = dir.join(name);
     fs::write(&script_path, script_body.as_bytes())?;
-    let output = Command::new(shell.to_string()).arg(&script_path).output()?;
+    let output = Command::new(shell.to_string())
+        .arg(&script_path)
+        // Keep the cache of command outputs, the files we keep for generated
+        // environments and the user's own config out of the user's home
+        // directory.
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .env("XDG_STATE_HOME", dir.join("state"))
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .output()?;
     Ok(output)
 }
 
@@ -129,14 +146,15 @@ pub fn run_command(shell: Shell, config: &toml::Table, command: &str) -> ScriptR
     })
 }
 
-fn execute_completion(
-    dir: &Path,
-    shell: Shell,
-    command: &str,
-    expected: &[&str],
-) -> eyre::Result<()> {
+/// Start an interactive shell in `dir`, with envswitch set up.
+fn spawn_shell(dir: &Path, shell: Shell) -> eyre::Result<PtySession> {
+    let mut command = shell.shell_command();
+    command
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .env("XDG_STATE_HOME", dir.join("state"))
+        .env("XDG_CONFIG_HOME", dir.join("config"));
     let mut p = spawn_with_options(
-        shell.shell_command(),
+        command,
         Options {
             timeout_ms: Some(1000),
             // There is a bug where leading characters get stripped in fish.
@@ -160,6 +178,16 @@ fn execute_completion(
     p.send_line("echo -n MARK; echo ER")?;
     p.flush()?;
     p.exp_string("MARKER")?;
+    Ok(p)
+}
+
+fn execute_completion(
+    dir: &Path,
+    shell: Shell,
+    command: &str,
+    expected: &[&str],
+) -> eyre::Result<()> {
+    let mut p = spawn_shell(dir, shell)?;
     p.send(command)?;
     p.send("\t")?;
     p.flush()?;
@@ -172,3 +200,14 @@ pub fn assert_completions(shell: Shell, config: &toml::Table, command: &str, exp
         execute_completion(dir, shell, command, expected)
     })
 }
+
+/// Run `test` against an interactive shell, for when we need a terminal.
+pub fn run_interactive(
+    shell: Shell,
+    config: &toml::Table,
+    test: impl Fn(&mut PtySession) -> eyre::Result<()>,
+) {
+    run_test(shell, config, |dir, shell| {
+        test(&mut spawn_shell(dir, shell)?)
+    })
+}
@@ -0,0 +1,87 @@
+//! Reading input from the user's terminal.
+//!
+//! Our stdout is `eval`ed by the shell function, so we talk to the controlling
+//! terminal directly instead.
+
+use eyre::eyre;
+
+/// Ask the user for a line of input, hiding what they type if `secret`.
+#[cfg(unix)]
+pub fn prompt(prompt: &str, secret: bool) -> eyre::Result<String> {
+    use std::{
+        fs::OpenOptions,
+        io::{Read, Write},
+    };
+
+    use eyre::Context;
+    use rustix::termios::{self, LocalModes, OptionalActions, SpecialCodeIndex};
+
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .wrap_err("Cannot prompt for a value without a terminal")?;
+
+    let original = termios::tcgetattr(&tty)?;
+    // We read one byte at a time and do our own echoing, so that we can see
+    // Ctrl-C as a byte rather than being killed with the terminal left in a
+    // bad state.
+    let mut raw = original.clone();
+    raw.local_modes
+        .remove(LocalModes::ICANON | LocalModes::ECHO | LocalModes::ISIG);
+    raw.special_codes[SpecialCodeIndex::VMIN] = 1;
+    raw.special_codes[SpecialCodeIndex::VTIME] = 0;
+    termios::tcsetattr(&tty, OptionalActions::Flush, &raw)?;
+
+    let mut read_line = || -> eyre::Result<String> {
+        write!(tty, "{prompt}: ")?;
+        let mut line = String::new();
+        let mut pending = Vec::new();
+        loop {
+            let mut byte = [0];
+            if tty.read(&mut byte)? == 0 {
+                return Err(eyre!("Reached end of input"));
+            }
+            match byte[0] {
+                b'\r' | b'\n' => break,
+                // Ctrl-C
+                0x03 => return Err(eyre!("Cancelled")),
+                // Ctrl-D
+                0x04 if line.is_empty() => return Err(eyre!("Reached end of input")),
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    if line.pop().is_some() && !secret {
+                        tty.write_all(b"\x08 \x08")?;
+                    }
+                }
+                // Ignore other control characters, such as those from arrow keys.
+                byte if byte.is_ascii_control() => (),
+                byte => {
+                    pending.push(byte);
+                    // Wait for the rest of a multi-byte character.
+                    if let Ok(ch) = str::from_utf8(&pending) {
+                        line.push_str(ch);
+                        if !secret {
+                            tty.write_all(&pending)?;
+                        }
+                        pending.clear();
+                    } else if pending.len() >= 4 {
+                        pending.clear();
+                    }
+                }
+            }
+        }
+        Ok(line)
+    };
+    let line = read_line();
+
+    termios::tcsetattr(&tty, OptionalActions::Flush, &original)?;
+    writeln!(tty)?;
+
+    line
+}
+
+#[cfg(not(unix))]
+pub fn prompt(_prompt: &str, _secret: bool) -> eyre::Result<String> {
+    Err(eyre!("Prompting for values is only supported on Unix"))
+}